@@ -1,35 +1,104 @@
-use crate::password_crypt;
+use crate::config::{Config, Retry, Storage};
+use crate::health::HEALTH;
+use crate::metrics::METRICS;
+use crate::retry;
+use crate::session::{self, SavedSession};
+use crate::shutdown::{Interrupted, SHUTDOWN};
+use crate::webhooks::{self, Event};
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::sync::RwLock;
 
-type SenderType = ();
+/// Storytel API client for one account. All requests take `&self` and may run
+/// concurrently; only the session token is behind a lock.
+pub struct StorytelClient {
+    http: reqwest::Client,
+    /// Account name reported to health checks and `login_failed` webhooks.
+    account: String,
+    webhooks: RwLock<Vec<String>>,
+    /// Never saves or removes the session file and reports no failed logins.
+    read_only: bool,
+    retry: RwLock<Retry>,
+    token: RwLock<String>,
+    /// Set when logging in again is possible, i.e. no fixed token is configured.
+    auth: RwLock<Option<Auth>>,
+    /// Serializes logins so a burst of rejected requests logs in once.
+    relogin: tokio::sync::Mutex<()>,
+}
 
-type ReceiverType = ();
-use serde::Deserialize;
+/// Credentials kept to log in again when Storytel rejects the session token.
+#[derive(Clone)]
+pub struct Auth {
+    pub email: String,
+    pub encrypted_password: String,
+    /// Where the session token is saved between runs.
+    pub session_file: PathBuf,
+}
 
-pub struct ClientData {
-    pub request_client: reqwest::Client,
-    pub login_data: Login,
-    #[allow(dead_code)]
-    pub sender: Option<SenderType>,
-    #[allow(dead_code)]
-    pub receiver: Option<ReceiverType>,
-    #[allow(dead_code)]
-    pub current_abookmark_id: Option<u64>,
-    #[allow(dead_code)]
-    pub current_abook_id: Option<u64>,
-    #[allow(dead_code)]
-    pub current_book_name: Option<String>,
+impl Auth {
+    fn from_config(cfg: &Config) -> Self {
+        Self {
+            email: cfg.email.clone(),
+            encrypted_password: cfg.encrypted_password(),
+            session_file: cfg.state_dir().join("session.json"),
+        }
+    }
+}
+
+/// Storytel did not accept the session token.
+#[derive(Debug)]
+struct SessionRejected;
+
+impl std::fmt::Display for SessionRejected {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("session token rejected")
+    }
+}
+
+impl std::error::Error for SessionRejected {}
+
+fn is_rejected(res: &eyre::Result<impl Sized>) -> bool {
+    res.as_ref()
+        .is_err_and(|e| e.downcast_ref::<SessionRejected>().is_some())
+}
+
+fn check_session(resp: &reqwest::Response) -> eyre::Result<()> {
+    match resp.status() {
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Err(SessionRejected.into()),
+        _ => Ok(()),
+    }
 }
 
 #[derive(Deserialize)]
-pub struct AccountInfo {
+struct AccountInfo {
     #[serde(rename = "singleSignToken")]
-    pub single_sign_token: String,
+    single_sign_token: String,
 }
 
 #[derive(Deserialize)]
-pub struct Login {
+struct Login {
     #[serde(rename = "accountInfo")]
-    pub account_info: AccountInfo,
+    account_info: AccountInfo,
+}
+
+/// Cache validators from a bookshelf response, sent back as
+/// `If-None-Match`/`If-Modified-Since`.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct Validators {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+/// Result of a conditional bookshelf request.
+pub enum ShelfFetch {
+    NotModified,
+    Fetched {
+        shelf: BookShelf,
+        /// Response body as received, for caching on disk.
+        body: String,
+        validators: Validators,
+    },
 }
 
 #[derive(Deserialize)]
@@ -55,6 +124,36 @@ pub struct BookEntry {
     pub description: Option<String>,
     #[serde(rename = "author")]
     pub author: Option<String>,
+    /// When the book was put on the shelf: the entry's `insertDate` from
+    /// `getBookShelf.action`. Storytel does not document the field, so it may
+    /// be missing; `added_within_days` then skips the book.
+    #[serde(rename = "insertDate", default, deserialize_with = "de_date")]
+    pub insert_date: Option<DateTime<Utc>>,
+}
+
+/// Accepts epoch milliseconds or a textual timestamp; anything else is `None`.
+fn de_date<'de, D>(de: D) -> Result<Option<DateTime<Utc>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    Ok(match serde_json::Value::deserialize(de)? {
+        serde_json::Value::Number(n) => n.as_i64().and_then(DateTime::from_timestamp_millis),
+        serde_json::Value::String(s) => DateTime::parse_from_rfc3339(&s)
+            .map(|d| d.with_timezone(&Utc))
+            .ok()
+            .or_else(|| {
+                NaiveDateTime::parse_from_str(&s, "%Y-%m-%d %H:%M:%S")
+                    .ok()
+                    .map(|d| d.and_utc())
+            })
+            .or_else(|| {
+                NaiveDate::parse_from_str(&s, "%Y-%m-%d")
+                    .ok()
+                    .and_then(|d| d.and_hms_opt(0, 0, 0))
+                    .map(|d| d.and_utc())
+            }),
+        _ => None,
+    })
 }
 
 #[allow(dead_code)]
@@ -71,6 +170,8 @@ pub struct Abook {
     pub id: u64,
     #[serde(rename = "isbn")]
     pub isbn: Option<String>,
+    #[serde(rename = "narratorAsString")]
+    pub narrator_as_string: Option<String>,
 }
 
 #[allow(dead_code)]
@@ -89,68 +190,366 @@ pub struct Book {
     pub description: Option<String>,
 }
 
-pub async fn login(client_data: &mut ClientData, email: &str, pass: &str) -> eyre::Result<()> {
-    let hex_encryp_pass = password_crypt::encrypt_password(pass.trim());
+impl StorytelClient {
+    /// Client without a session; call [`Self::authenticate`] or [`Self::login`] next.
+    pub fn new(http: reqwest::Client, retry: Retry) -> Self {
+        Self {
+            http,
+            account: String::new(),
+            webhooks: RwLock::new(Vec::new()),
+            read_only: false,
+            retry: RwLock::new(retry),
+            token: RwLock::new(String::new()),
+            auth: RwLock::new(None),
+            relogin: tokio::sync::Mutex::new(()),
+        }
+    }
 
-    let url = format!(
-        "https://www.storytel.com/api/login.action\
-                      ?m=1&uid={}&pwd={}",
-        email.trim(),
-        hex_encryp_pass
-    );
+    /// Client for the account `name` of the config, reporting failed logins.
+    pub fn for_account(http: reqwest::Client, name: &str, cfg: &Config) -> Self {
+        Self {
+            account: name.to_owned(),
+            webhooks: RwLock::new(cfg.webhooks.clone()),
+            ..Self::new(http, cfg.retry.clone())
+        }
+    }
 
-    let resp_login = client_data.request_client.get(&url).send().await?;
-    client_data.login_data = resp_login.json::<Login>().await?;
-    Ok(())
-}
+    /// Client for dry runs: it reuses a saved session or logs in, but leaves
+    /// the state directory, health checks and webhooks alone.
+    pub fn read_only(http: reqwest::Client, cfg: &Config) -> Self {
+        Self {
+            read_only: true,
+            ..Self::new(http, cfg.retry.clone())
+        }
+    }
 
-pub async fn get_bookshelf(client_data: &mut ClientData) -> eyre::Result<BookShelf> {
-    let url_get_bookshelf = format!(
-        "https://www.storytel.com/api/getBookShelf.\
-                                    action?token={}",
-        client_data.login_data.account_info.single_sign_token
-    );
-    let resp_bookshelf = client_data
-        .request_client
-        .get(&url_get_bookshelf)
-        .send()
-        .await?;
-    Ok(resp_bookshelf.json::<BookShelf>().await?)
-}
+    pub fn token(&self) -> String {
+        self.token.read().unwrap().clone()
+    }
 
-pub async fn get_stream_url(client_data: &mut ClientData, id: u64) -> eyre::Result<String> {
-    let url_ask_stream = format!(
-        "https://www.storytel.com/mp3streamRangeReq\
-                                 ?startposition=0&programId={}&token={}",
-        id, client_data.login_data.account_info.single_sign_token
-    );
+    /// Whether a session token is present; false after a failed login.
+    pub fn is_logged_in(&self) -> bool {
+        !self.token.read().unwrap().is_empty()
+    }
 
-    let resp = client_data
-        .request_client
-        .get(&url_ask_stream)
-        .send()
-        .await?;
-    let loc = resp
-        .headers()
-        .get("location")
-        .ok_or_else(|| eyre::eyre!("missing location header"))?
-        .to_str()?
-        .to_string();
-    Ok(loc)
+    /// Logs in unless a session token is present.
+    pub async fn ensure_login(&self) -> eyre::Result<()> {
+        if self.is_logged_in() {
+            return Ok(());
+        }
+        self.relogin("").await
+    }
+
+    fn retry(&self) -> Retry {
+        self.retry.read().unwrap().clone()
+    }
+
+    fn auth(&self) -> Option<Auth> {
+        self.auth.read().unwrap().clone()
+    }
+
+    fn set_token(&self, token: String) {
+        *self.token.write().unwrap() = token;
+    }
+
+    /// Adopts the configured token, reuses the session saved by an earlier run,
+    /// or logs in with the configured credentials.
+    pub async fn authenticate(&mut self, cfg: &Config) -> eyre::Result<()> {
+        if let Some(token) = &cfg.token {
+            self.set_token(token.clone());
+            return Ok(());
+        }
+        let auth = Auth::from_config(cfg);
+        let saved = session::load(&auth.session_file).filter(|s| s.email == auth.email);
+        *self.auth.get_mut().unwrap() = Some(auth);
+        if let Some(saved) = saved {
+            tracing::info!("authenticate: reusing the saved Storytel session");
+            self.set_token(saved.token);
+            return Ok(());
+        }
+        self.relogin("").await
+    }
+
+    /// Applies reloaded settings. Changed credentials log in again right away
+    /// and a changed token replaces the session.
+    pub async fn reconfigure(&self, cfg: &Config) -> eyre::Result<()> {
+        *self.retry.write().unwrap() = cfg.retry.clone();
+        *self.webhooks.write().unwrap() = cfg.webhooks.clone();
+        if let Some(token) = &cfg.token {
+            *self.auth.write().unwrap() = None;
+            if self.token() != *token {
+                self.set_token(token.clone());
+                HEALTH.set_login_failed(&self.account, false);
+            }
+            return Ok(());
+        }
+        let auth = Auth::from_config(cfg);
+        let changed = self.auth().is_none_or(|old| {
+            old.email != auth.email || old.encrypted_password != auth.encrypted_password
+        });
+        *self.auth.write().unwrap() = Some(auth);
+        if changed {
+            tracing::info!("reconfigure: credentials changed, logging in again");
+            self.relogin(&self.token()).await?;
+        }
+        Ok(())
+    }
+
+    /// Logs in with [`Self::auth`] and saves the new session, unless another
+    /// request already replaced the `rejected` token. Every login goes
+    /// through here, so this is where health and webhooks learn about it.
+    async fn relogin(&self, rejected: &str) -> eyre::Result<()> {
+        let _guard = self.relogin.lock().await;
+        if self.token() != rejected {
+            return Ok(());
+        }
+        let res = self.login_with_auth().await;
+        if self.read_only {
+            return res;
+        }
+        HEALTH.set_login_failed(&self.account, res.is_err());
+        if let Err(e) = &res {
+            let email = self.auth().map(|a| a.email).unwrap_or_default();
+            webhooks::emit(
+                &self.webhooks.read().unwrap(),
+                Event::LoginFailed {
+                    account: self.account.clone(),
+                    email,
+                    error: format!("{e:#}"),
+                },
+            );
+        }
+        res
+    }
+
+    async fn login_with_auth(&self) -> eyre::Result<()> {
+        let Some(auth) = self.auth() else {
+            eyre::bail!(
+                "the configured token was rejected; create a new one with encrypt-password"
+            );
+        };
+        let res = self.login(&auth.email, &auth.encrypted_password).await;
+        if self.read_only {
+            return res;
+        }
+        if let Err(e) = res {
+            session::remove(&auth.session_file);
+            return Err(e);
+        }
+        let saved = SavedSession {
+            email: auth.email.clone(),
+            token: self.token(),
+            saved_at: Utc::now().timestamp(),
+        };
+        if let Err(e) = session::save(&auth.session_file, &saved) {
+            tracing::warn!("login: cannot save session: {e:#}");
+        }
+        Ok(())
+    }
+
+    /// `hex_encryp_pass` is the password as returned by [`crate::password_crypt::encrypt_password`].
+    pub async fn login(&self, email: &str, hex_encryp_pass: &str) -> eyre::Result<()> {
+        let url = format!(
+            "https://www.storytel.com/api/login.action\
+                          ?m=1&uid={}&pwd={}",
+            email.trim(),
+            hex_encryp_pass
+        );
+
+        let res = async {
+            let resp_login = retry::send(&self.retry(), "login", || self.http.get(&url)).await?;
+            Ok(resp_login.json::<Login>().await?)
+        }
+        .await;
+        match METRICS.observe_api("login", res) {
+            Ok(login) => {
+                self.set_token(login.account_info.single_sign_token);
+                Ok(())
+            }
+            Err(e) => {
+                METRICS.login_failed();
+                Err(e)
+            }
+        }
+    }
+
+    pub async fn get_bookshelf(&self) -> eyre::Result<BookShelf> {
+        match self
+            .get_bookshelf_if_changed(&Validators::default())
+            .await?
+        {
+            ShelfFetch::Fetched { shelf, .. } => Ok(shelf),
+            ShelfFetch::NotModified => eyre::bail!("unexpected 304 for an unconditional request"),
+        }
+    }
+
+    /// Fetches the bookshelf unless it still matches `validators` from an
+    /// earlier response.
+    pub async fn get_bookshelf_if_changed(
+        &self,
+        validators: &Validators,
+    ) -> eyre::Result<ShelfFetch> {
+        let token = self.token();
+        let res = self.fetch_bookshelf(&token, validators).await;
+        if is_rejected(&res) && self.auth().is_some() {
+            tracing::info!("get_bookshelf: session expired, logging in again");
+            self.relogin(&token).await?;
+            return self.fetch_bookshelf(&self.token(), validators).await;
+        }
+        res
+    }
+
+    async fn fetch_bookshelf(
+        &self,
+        token: &str,
+        validators: &Validators,
+    ) -> eyre::Result<ShelfFetch> {
+        use reqwest::header;
+
+        let url_get_bookshelf = format!(
+            "https://www.storytel.com/api/getBookShelf.\
+                                        action?token={token}"
+        );
+        let res = async {
+            let resp_bookshelf = retry::send(&self.retry(), "get_bookshelf", || {
+                let mut req = self.http.get(&url_get_bookshelf);
+                if let Some(etag) = &validators.etag {
+                    req = req.header(header::IF_NONE_MATCH, etag);
+                }
+                if let Some(modified) = &validators.last_modified {
+                    req = req.header(header::IF_MODIFIED_SINCE, modified);
+                }
+                req
+            })
+            .await?;
+            check_session(&resp_bookshelf)?;
+            if resp_bookshelf.status() == StatusCode::NOT_MODIFIED {
+                return Ok(ShelfFetch::NotModified);
+            }
+            let value = |name| {
+                resp_bookshelf
+                    .headers()
+                    .get(name)
+                    .and_then(|v| v.to_str().ok())
+                    .map(str::to_owned)
+            };
+            let validators = Validators {
+                etag: value(header::ETAG),
+                last_modified: value(header::LAST_MODIFIED),
+            };
+            let body = resp_bookshelf.text().await?;
+            let shelf = serde_json::from_str::<BookShelf>(&body).map_err(|e| {
+                // error replies are JSON without a shelf
+                match serde_json::from_str::<serde_json::Value>(&body) {
+                    Ok(v) if v.get("books").is_none() => SessionRejected.into(),
+                    _ => eyre::Report::from(e),
+                }
+            })?;
+            Ok(ShelfFetch::Fetched {
+                shelf,
+                body,
+                validators,
+            })
+        }
+        .await;
+        METRICS.observe_api("get_bookshelf", res)
+    }
+
+    pub async fn get_stream_url(&self, id: u64) -> eyre::Result<String> {
+        let token = self.token();
+        let res = self.fetch_stream_url(&token, id).await;
+        if is_rejected(&res) && self.auth().is_some() {
+            tracing::info!("get_stream_url: session expired, logging in again");
+            self.relogin(&token).await?;
+            return self.fetch_stream_url(&self.token(), id).await;
+        }
+        res
+    }
+
+    async fn fetch_stream_url(&self, token: &str, id: u64) -> eyre::Result<String> {
+        let url_ask_stream = format!(
+            "https://www.storytel.com/mp3streamRangeReq\
+                                     ?startposition=0&programId={id}&token={token}"
+        );
+
+        let res = async {
+            let resp = retry::send(&self.retry(), "get_stream_url", || {
+                self.http.get(&url_ask_stream)
+            })
+            .await?;
+            check_session(&resp)?;
+            let loc = resp
+                .headers()
+                .get("location")
+                .ok_or_else(|| {
+                    if resp.status().is_redirection() {
+                        eyre::eyre!("missing location header")
+                    } else {
+                        SessionRejected.into()
+                    }
+                })?
+                .to_str()?
+                .to_string();
+            Ok(loc)
+        }
+        .await;
+        METRICS.observe_api("get_stream_url", res)
+    }
+
+    #[allow(dead_code)]
+    pub async fn set_bookmark(&self, abookmark_id: u64, position: i64) -> eyre::Result<()> {
+        let microsec_to_sec = 1_000_000;
+        let params = [
+            ("token", self.token()),
+            ("bookId", abookmark_id.to_string()),
+            ("pos", (position * microsec_to_sec).to_string()),
+            ("type", "1".to_string()),
+        ];
+        let url_set_bookmark = "https://www.storytel.se/api/setABookmark.action".to_string();
+        let res = self
+            .http
+            .post(url_set_bookmark)
+            .form(&params)
+            .send()
+            .await
+            .map_err(eyre::Report::from);
+        METRICS.observe_api("set_bookmark", res)?;
+        Ok(())
+    }
 }
 
-use std::path::Path;
+use crate::throttle::RateLimiter;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Settings applied to every audio download.
+#[derive(Clone)]
+pub struct DownloadOptions {
+    /// Root of the library; free space and size limits are checked here.
+    pub library_root: PathBuf,
+    pub storage: Storage,
+    /// Library size in bytes if the caller keeps track of it; measured
+    /// before the download otherwise.
+    pub library_used: Option<u64>,
+    pub rate_limit: Arc<RateLimiter>,
+    pub retry: Retry,
+}
 
 pub async fn download_stream_with_progress<F>(
     stream_url: &str,
     book_path: &Path,
+    opts: &DownloadOptions,
     mut progress: F,
-) -> eyre::Result<()>
+) -> eyre::Result<u64>
 where
     F: FnMut(u64, Option<u64>) + Send + 'static,
 {
     use futures_util::StreamExt;
-    use tokio::{fs, io::AsyncWriteExt};
+    use reqwest::header;
+    use tokio::{
+        fs,
+        io::{AsyncSeekExt, AsyncWriteExt},
+    };
 
     tracing::debug!(
         "download_stream_with_progress: url={}, dst={:?}",
@@ -158,48 +557,211 @@ where
         book_path
     );
 
+    let _active = METRICS.start_download();
+    let started = std::time::Instant::now();
+
+    // data goes to a .part file that only becomes audio.mp3 when complete;
+    // one left by a shutdown is continued
     fs::create_dir_all(book_path).await?;
-    let mut file = fs::File::create(book_path.join("audio.mp3")).await?;
+    let target = book_path.join("audio.mp3");
+    let part = book_path.join("audio.mp3.part");
+    let mut downloaded = fs::metadata(&part).await.map_or(0, |m| m.len());
+    if downloaded > 0 {
+        tracing::info!(
+            "download: continuing {} at {downloaded} bytes",
+            part.display()
+        );
+    }
 
-    let resp = reqwest::get(stream_url).await?;
-    let total = resp.content_length();
-    let mut downloaded = 0u64;
+    let http = reqwest::Client::new();
+    let request = |from: u64| {
+        let req = http.get(stream_url);
+        if from > 0 {
+            req.header(header::RANGE, format!("bytes={from}-"))
+        } else {
+            req
+        }
+    };
+    let resp = retry::send(&opts.retry, "download", || request(downloaded)).await?;
+    if resp.status() != StatusCode::PARTIAL_CONTENT {
+        downloaded = 0;
+    }
+    let total = resp.content_length().map(|len| len + downloaded);
+    crate::storage::check(
+        &opts.storage,
+        &opts.library_root,
+        total.map_or(0, |t| t - downloaded),
+        opts.library_used,
+    )?;
+
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(false)
+        .open(&part)
+        .await?;
+    file.set_len(downloaded).await?;
+    file.seek(std::io::SeekFrom::Start(downloaded)).await?;
 
-    let mut stream = resp.bytes_stream();
-    while let Some(chunk) = stream.next().await {
-        let chunk = chunk?;
-        file.write_all(&chunk).await?;
-        downloaded += chunk.len() as u64;
-        progress(downloaded, total);
+    let res = async {
+        let mut resp = Some(resp);
+        let mut interruptions = 0;
+        loop {
+            let resp = match resp.take() {
+                Some(resp) => resp,
+                None => {
+                    // resume where the interrupted stream stopped
+                    let resp = retry::send(&opts.retry, "download", || request(downloaded)).await?;
+                    if resp.status() != StatusCode::PARTIAL_CONTENT {
+                        tracing::warn!("download: server ignored the range request, restarting");
+                        file.set_len(0).await?;
+                        file.seek(std::io::SeekFrom::Start(0)).await?;
+                        downloaded = 0;
+                    }
+                    resp
+                }
+            };
+            let resp = resp.error_for_status()?;
+
+            let mut stream = resp.bytes_stream();
+            let mut interrupted = None;
+            loop {
+                let chunk = tokio::select! {
+                    chunk = stream.next() => chunk,
+                    () = SHUTDOWN.checkpoint_requested() => return Err(Interrupted.into()),
+                };
+                let chunk = match chunk {
+                    Some(Ok(chunk)) => chunk,
+                    Some(Err(e)) => {
+                        interrupted = Some(e);
+                        break;
+                    }
+                    None => break,
+                };
+                // only consecutive failures without progress count
+                interruptions = 0;
+                opts.rate_limit.consume(chunk.len() as u64).await;
+                file.write_all(&chunk).await?;
+                downloaded += chunk.len() as u64;
+                METRICS.add_downloaded_bytes(chunk.len() as u64);
+                progress(downloaded, total);
+            }
+            let Some(e) = interrupted else { break };
+            interruptions += 1;
+            if !retry::retryable_error(&e) || interruptions >= opts.retry.max_attempts {
+                return Err(e.into());
+            }
+            let wait = opts.retry.backoff(interruptions);
+            tracing::warn!(
+                "download: stream interrupted at {downloaded} bytes ({e}), resuming in {:.1}s",
+                wait.as_secs_f64()
+            );
+            tokio::time::sleep(wait).await;
+        }
+        file.flush().await?;
+        if let Some(total) = total.filter(|t| *t != downloaded) {
+            eyre::bail!("received {downloaded} bytes, expected {total}");
+        }
+        eyre::Ok(())
+    }
+    .await;
+    if let Err(e) = res {
+        if e.downcast_ref::<Interrupted>().is_some() {
+            file.flush().await?;
+            file.sync_all().await?;
+            tracing::info!(
+                "download: checkpointed {} at {downloaded} bytes",
+                part.display()
+            );
+        } else {
+            drop(file);
+            let _ = fs::remove_file(&part).await;
+        }
+        return Err(e);
     }
-    Ok(())
+    file.sync_all().await?;
+    drop(file);
+    fs::rename(&part, &target).await?;
+    METRICS.observe_download(started.elapsed());
+    Ok(downloaded)
 }
 
-#[allow(dead_code)]
-pub async fn set_bookmark(client_data: &mut ClientData, position: i64) -> eyre::Result<()> {
-    let microsec_to_sec = 1_000_000;
-    let params = [
-        (
-            "token",
-            client_data
-                .login_data
-                .account_info
-                .single_sign_token
-                .to_string(),
-        ),
-        (
-            "bookId",
-            client_data.current_abookmark_id.unwrap().to_string(),
-        ),
-        ("pos", (position * microsec_to_sec).to_string()),
-        ("type", "1".to_string()),
-    ];
-    let url_set_bookmark = "https://www.storytel.se/api/setABookmark.action".to_string();
-    client_data
-        .request_client
-        .post(url_set_bookmark)
-        .form(&params)
-        .send()
-        .await?;
-    Ok(())
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Serves `response` once and returns the request it received.
+    async fn stub_server(response: &'static str) -> (String, tokio::task::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/audio.mp3", listener.local_addr().unwrap());
+        let handle = tokio::spawn(async move {
+            let (mut sock, _) = listener.accept().await.unwrap();
+            let mut buf = [0; 4096];
+            let n = sock.read(&mut buf).await.unwrap();
+            sock.write_all(response.as_bytes()).await.unwrap();
+            String::from_utf8_lossy(&buf[..n]).to_lowercase()
+        });
+        (url, handle)
+    }
+
+    fn options(root: &Path) -> DownloadOptions {
+        DownloadOptions {
+            library_root: root.to_owned(),
+            storage: Storage {
+                min_free_bytes: 0,
+                max_library_bytes: None,
+            },
+            library_used: None,
+            rate_limit: Arc::new(RateLimiter::new(None, None)),
+            retry: Retry::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn continues_part_file() {
+        let root =
+            std::env::temp_dir().join(format!("storytel-sync-resume-{}", std::process::id()));
+        let book = root.join("author/title");
+        std::fs::create_dir_all(&book).unwrap();
+        std::fs::write(book.join("audio.mp3.part"), b"hello ").unwrap();
+
+        let (url, server) = stub_server(
+            "HTTP/1.1 206 Partial Content\r\ncontent-range: bytes 6-10/11\r\n\
+             content-length: 5\r\nconnection: close\r\n\r\nworld",
+        )
+        .await;
+        let len = download_stream_with_progress(&url, &book, &options(&root), |_, _| {})
+            .await
+            .unwrap();
+        assert!(server.await.unwrap().contains("range: bytes=6-\r\n"));
+        assert_eq!(len, 11);
+        assert_eq!(
+            std::fs::read(book.join("audio.mp3")).unwrap(),
+            b"hello world"
+        );
+        assert!(!book.join("audio.mp3.part").exists());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn restarts_when_range_is_ignored() {
+        let root =
+            std::env::temp_dir().join(format!("storytel-sync-restart-{}", std::process::id()));
+        let book = root.join("author/title");
+        std::fs::create_dir_all(&book).unwrap();
+        std::fs::write(book.join("audio.mp3.part"), b"stale data").unwrap();
+
+        let (url, server) =
+            stub_server("HTTP/1.1 200 OK\r\ncontent-length: 5\r\nconnection: close\r\n\r\nfresh")
+                .await;
+        let len = download_stream_with_progress(&url, &book, &options(&root), |_, _| {})
+            .await
+            .unwrap();
+        assert!(server.await.unwrap().contains("range: bytes=10-\r\n"));
+        assert_eq!(len, 5);
+        assert_eq!(std::fs::read(book.join("audio.mp3")).unwrap(), b"fresh");
+        std::fs::remove_dir_all(&root).unwrap();
+    }
 }
@@ -1,22 +1,771 @@
+use crate::client_storytel_api::DownloadOptions;
+use crate::throttle::RateLimiter;
+use eyre::WrapErr;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+/// Prefix of environment variables overriding config keys, e.g.
+/// `STORYTEL_SYNC_SCHEDULE__CRON` for `schedule.cron`.
+const ENV_PREFIX: &str = "STORYTEL_SYNC_";
+/// `STORYTEL_SYNC_*` variables read by the command line instead.
+const ENV_CLI: [&str; 3] = ["CONFIG", "LISTEN_HOST", "LISTEN_PORT"];
+
+/// Variables Kubernetes injects for a Service named `storytel-sync`, such as
+/// `STORYTEL_SYNC_PORT=tcp://10.0.0.1:8080` or `STORYTEL_SYNC_SERVICE_HOST`.
+fn is_service_link(var: &str) -> bool {
+    var == "PORT"
+        || var.starts_with("SERVICE_HOST")
+        || var.starts_with("SERVICE_PORT")
+        || var
+            .strip_prefix("PORT_")
+            .is_some_and(|rest| rest.starts_with(|c: char| c.is_ascii_digit()))
+}
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Config {
+    #[serde(default)]
     pub email: String,
+    /// File holding the email, e.g. a Docker secret; replaces `email`.
+    pub email_file: Option<PathBuf>,
+    #[serde(default)]
     pub password: String,
+    /// File holding the password, e.g. a Docker secret; replaces `password`.
+    pub password_file: Option<PathBuf>,
+    /// Output of `storytel-sync encrypt-password`; replaces `password`.
+    pub password_encrypted: Option<String>,
+    /// Single-sign token from `storytel-sync encrypt-password --token`; used
+    /// instead of logging in.
+    pub token: Option<String>,
     pub download_dir: PathBuf,
     #[serde(default)]
     pub sync_enabled: bool,
+    #[serde(default)]
+    pub schedule: Schedule,
+    #[serde(default)]
+    pub filters: Filters,
+    #[serde(default)]
+    pub prune: Prune,
+    #[serde(default)]
+    pub storage: Storage,
+    #[serde(default)]
+    pub bandwidth: Bandwidth,
+    #[serde(default)]
+    pub retry: Retry,
+    #[serde(default)]
+    pub cache: Cache,
+    #[serde(default)]
+    pub reload: Reload,
+    #[serde(default)]
+    pub shutdown: Shutdown,
+    /// Where bookkeeping is kept; defaults to `<download_dir>/.storytel-sync`.
+    pub state_dir: Option<PathBuf>,
+    #[serde(default)]
+    pub media_servers: Vec<MediaServer>,
+    #[serde(default)]
+    pub webhooks: Vec<String>,
+    /// Several Storytel accounts served by one instance; replaces the
+    /// top-level credentials.
+    #[serde(default)]
+    pub accounts: Vec<Account>,
+    /// Link books another account already downloaded instead of fetching
+    /// them again, matched by ISBN.
+    #[serde(default)]
+    pub dedupe_by_isbn: bool,
+}
+
+/// One entry of `[[accounts]]`. Unset sync settings fall back to the top level.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Account {
+    /// Shown in the web UI and used in URLs.
+    pub name: String,
+    #[serde(default)]
+    pub email: String,
+    pub email_file: Option<PathBuf>,
+    #[serde(default)]
+    pub password: String,
+    pub password_file: Option<PathBuf>,
+    pub password_encrypted: Option<String>,
+    pub token: Option<String>,
+    /// Directory under `download_dir`; defaults to `name`.
+    pub download_subdir: Option<PathBuf>,
+    pub sync_enabled: Option<bool>,
+    pub schedule: Option<Schedule>,
+    pub filters: Option<Filters>,
+}
+
+/// When background sync passes run.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct Schedule {
+    /// Cron expression (`min hour dom month dow`); replaces `interval_secs` when set.
+    pub cron: Option<String>,
+    pub interval_secs: u64,
+    /// Random offset of up to +/- this many seconds applied to every run;
+    /// 7200 with `interval_secs` and 0 with `cron` unless set.
+    pub jitter_secs: Option<u64>,
+    /// Delay before the first pass when neither `run_on_start` nor `cron` is set.
+    pub initial_delay_secs: u64,
+    pub run_on_start: bool,
+    /// Daily `HH:MM-HH:MM` window in which background downloads may not start.
+    pub quiet_hours: Option<String>,
+}
+
+impl Default for Schedule {
+    fn default() -> Self {
+        Self {
+            cron: None,
+            interval_secs: 86_400,
+            jitter_secs: None,
+            initial_delay_secs: 600,
+            run_on_start: false,
+            quiet_hours: None,
+        }
+    }
+}
+
+/// Which bookshelf entries the background sync downloads.
+///
+/// Author and title patterns are case-insensitive globs, or regexes when
+/// written as `/.../`. When any `include_*` list is set a book must match one
+/// of them; `exclude_*` rules always win.
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct Filters {
+    pub include_authors: Vec<String>,
+    pub exclude_authors: Vec<String>,
+    pub include_titles: Vec<String>,
+    pub exclude_titles: Vec<String>,
+    pub include_ids: Vec<u64>,
+    pub exclude_ids: Vec<u64>,
+    pub include_isbns: Vec<String>,
+    pub exclude_isbns: Vec<String>,
+    /// Only books with a bookmark position > 0.
+    pub only_started: bool,
+    /// Only books added to the shelf in the last N days; books whose add date
+    /// Storytel does not report are skipped.
+    pub added_within_days: Option<u32>,
+}
+
+/// Disk usage limits checked before every download.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct Storage {
+    /// Free space under `download_dir` that downloads must leave untouched.
+    pub min_free_bytes: u64,
+    /// Background sync pauses once the library reaches this size.
+    pub max_library_bytes: Option<u64>,
+}
+
+impl Default for Storage {
+    fn default() -> Self {
+        Self {
+            min_free_bytes: 1 << 30,
+            max_library_bytes: None,
+        }
+    }
+}
+
+/// Download speed limits; unset means unlimited.
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct Bandwidth {
+    pub sync_bytes_per_sec: Option<u64>,
+    pub download_bytes_per_sec: Option<u64>,
+    /// Daily `HH:MM-HH:MM` window in which no limit applies.
+    pub unthrottled_hours: Option<String>,
+}
+
+/// Retries of Storytel API calls and audio downloads after connection
+/// errors, timeouts, `429` and `5xx` responses.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct Retry {
+    /// Tries per request including the first; `1` disables retries.
+    pub max_attempts: u32,
+    /// Delay before the first retry, doubled for every further one.
+    pub initial_backoff_ms: u64,
+    pub max_backoff_secs: u64,
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            initial_backoff_ms: 1_000,
+            max_backoff_secs: 60,
+        }
+    }
+}
+
+/// How long the bookshelf fetched from Storytel is reused.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct Cache {
+    /// Page views within this many seconds of the last fetch use the cached
+    /// shelf; sync passes always ask Storytel.
+    pub bookshelf_ttl_secs: u64,
+}
+
+impl Default for Cache {
+    fn default() -> Self {
+        Self {
+            bookshelf_ttl_secs: 300,
+        }
+    }
+}
+
+/// Applying config file changes without a restart.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct Reload {
+    /// Reload when the config file changes; SIGHUP and
+    /// `POST /api/config/reload` always work.
+    pub watch: bool,
+    /// How often the file's modification time is checked.
+    pub poll_secs: u64,
+}
+
+impl Default for Reload {
+    fn default() -> Self {
+        Self {
+            watch: true,
+            poll_secs: 5,
+        }
+    }
+}
+
+/// What happens to running downloads on SIGTERM.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct Shutdown {
+    /// Time the whole shutdown may take. Open HTTP connections are closed
+    /// and running downloads finish within it; downloads still running
+    /// shortly before it ends are checkpointed and continued after the
+    /// restart. Must stay below the stop timeout of the container runtime.
+    pub grace_secs: u64,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        // leaves room for the state flush within the 10 s `docker stop`
+        // allows by default
+        Self { grace_secs: 5 }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PrunePolicy {
+    /// Ignore local books that left the shelf.
+    Keep,
+    /// Show them on the bookshelf page.
+    List,
+    /// Move them to `archive_dir` after the grace period.
+    Archive,
+    /// Delete them after the grace period.
+    Delete,
+}
+
+/// What happens to downloaded books that are no longer on the Storytel shelf.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct Prune {
+    pub policy: PrunePolicy,
+    pub grace_days: u32,
+    pub archive_dir: Option<PathBuf>,
+    /// Refuse to archive/delete anything when a larger share of the local
+    /// library would be affected; a single book is always allowed.
+    pub max_fraction: f64,
+}
+
+impl Default for Prune {
+    fn default() -> Self {
+        Self {
+            policy: PrunePolicy::List,
+            grace_days: 30,
+            archive_dir: None,
+            max_fraction: 0.25,
+        }
+    }
+}
+
+impl Prune {
+    fn validate(&self) -> eyre::Result<()> {
+        if self.policy == PrunePolicy::Archive && self.archive_dir.is_none() {
+            eyre::bail!("prune.archive_dir is required for policy = \"archive\"");
+        }
+        if !(0.0..=1.0).contains(&self.max_fraction) {
+            eyre::bail!("prune.max_fraction must be between 0 and 1");
+        }
+        Ok(())
+    }
+}
+
+/// Media server whose library scan is triggered after a sync pass or an
+/// on-demand download finished.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum MediaServer {
+    Audiobookshelf {
+        url: String,
+        token: String,
+        library_id: String,
+    },
+    Jellyfin {
+        url: String,
+        token: String,
+    },
 }
 
 impl Config {
-    pub fn load(path: &Path) -> eyre::Result<Self> {
-        let content = std::fs::read_to_string(path)?;
-        if path.extension().and_then(|s| s.to_str()) == Some("json") {
-            Ok(serde_json::from_str(&content)?)
+    pub fn download_options(&self, rate_limit: &Arc<RateLimiter>) -> DownloadOptions {
+        DownloadOptions {
+            library_root: self.download_dir.clone(),
+            storage: self.storage.clone(),
+            library_used: None,
+            rate_limit: rate_limit.clone(),
+            retry: self.retry.clone(),
+        }
+    }
+
+    pub fn state_dir(&self) -> PathBuf {
+        self.state_dir
+            .clone()
+            .unwrap_or_else(|| self.download_dir.join(".storytel-sync"))
+    }
+
+    /// Reads `path`, then applies `STORYTEL_SYNC_*` environment variables and
+    /// the `KEY=VALUE` pairs of `overrides` (`--set`), later sources winning.
+    pub fn load(path: &Path, overrides: &[String]) -> eyre::Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .wrap_err_with(|| format!("cannot read {}", path.display()))?;
+        let mut doc: Value = if path.extension().and_then(|s| s.to_str()) == Some("json") {
+            serde_json::from_str(&content)?
+        } else {
+            toml::from_str(&content)?
+        };
+        eyre::ensure!(doc.is_object(), "{}: expected a table", path.display());
+
+        let template = template()?;
+        let mut origins = HashMap::new();
+        let mut env: Vec<_> = std::env::vars()
+            .filter_map(|(k, v)| Some((k.strip_prefix(ENV_PREFIX)?.to_owned(), v)))
+            .filter(|(k, _)| !ENV_CLI.contains(&k.as_str()) && !is_service_link(k))
+            .collect();
+        env.sort();
+        for (var, value) in env {
+            let key = var.to_lowercase().replace("__", ".");
+            set_key(&mut doc, &template, &key, &value)
+                .wrap_err_with(|| format!("{ENV_PREFIX}{var}"))?;
+            origins.insert(key, format!("{ENV_PREFIX}{var}"));
+        }
+        for o in overrides {
+            let (key, value) = o
+                .split_once('=')
+                .ok_or_else(|| eyre::eyre!("--set {o}: expected KEY=VALUE"))?;
+            set_key(&mut doc, &template, key, value).wrap_err_with(|| format!("--set {key}"))?;
+            origins.insert(key.to_owned(), format!("--set {key}"));
+        }
+
+        let mut unknown = |key: serde_ignored::Path<'_>| {
+            tracing::warn!("config: ignoring unknown key `{key}`");
+        };
+        let de = serde_ignored::Deserializer::new(doc, &mut unknown);
+        let mut cfg: Self = serde_path_to_error::deserialize(de).map_err(|e| {
+            let key = e.path().to_string();
+            let from = origins
+                .iter()
+                .find(|(k, _)| {
+                    key.strip_prefix(k.as_str())
+                        .is_some_and(|rest| rest.is_empty() || rest.starts_with(['.', '[']))
+                })
+                .map_or_else(|| path.display().to_string(), |(_, o)| o.clone());
+            eyre::eyre!("invalid config key `{key}` (from {from}): {}", e.inner())
+        })?;
+        if cfg.accounts.is_empty() {
+            cfg.resolve_credentials()?;
         } else {
-            Ok(toml::from_str(&content)?)
+            eyre::ensure!(
+                cfg.email.is_empty()
+                    && cfg.email_file.is_none()
+                    && cfg.password.is_empty()
+                    && cfg.password_file.is_none()
+                    && cfg.password_encrypted.is_none()
+                    && cfg.token.is_none(),
+                "with [[accounts]], credentials belong to each account"
+            );
+        }
+
+        cfg.schedule.validate()?;
+        crate::filters::Selection::compile(&cfg.filters).wrap_err("filters")?;
+        cfg.prune.validate()?;
+        crate::throttle::Throttles::new(&cfg.bandwidth)?;
+        cfg.retry.validate()?;
+        eyre::ensure!(
+            cfg.reload.poll_secs > 0,
+            "reload.poll_secs must be at least 1"
+        );
+        cfg.accounts()?;
+        Ok(cfg)
+    }
+
+    /// Libraries of the other accounts when `dedupe_by_isbn` is on; `dirs`
+    /// are the download directories of all accounts.
+    pub fn dedupe_dirs(&self, dirs: &[PathBuf], own: &Path) -> Vec<PathBuf> {
+        if !self.dedupe_by_isbn {
+            return Vec::new();
+        }
+        dirs.iter().filter(|d| *d != own).cloned().collect()
+    }
+
+    /// Effective configuration of every account, each with its own
+    /// credentials, `download_dir`, `state_dir` and sync settings.
+    /// Without `[[accounts]]` this is the config itself, named `default`.
+    pub fn accounts(&self) -> eyre::Result<Vec<(String, Config)>> {
+        if self.accounts.is_empty() {
+            return Ok(vec![("default".to_owned(), self.clone())]);
+        }
+        let mut seen = std::collections::HashSet::new();
+        self.accounts
+            .iter()
+            .map(|a| {
+                let name = &a.name;
+                eyre::ensure!(
+                    !name.is_empty()
+                        && name
+                            .chars()
+                            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'),
+                    "accounts: name {name:?} may only contain letters, digits, `-` and `_`"
+                );
+                eyre::ensure!(seen.insert(name), "accounts: duplicate name {name:?}");
+
+                let mut cfg = self.clone();
+                cfg.accounts.clear();
+                cfg.email.clone_from(&a.email);
+                cfg.email_file.clone_from(&a.email_file);
+                cfg.password.clone_from(&a.password);
+                cfg.password_file.clone_from(&a.password_file);
+                cfg.password_encrypted.clone_from(&a.password_encrypted);
+                cfg.token.clone_from(&a.token);
+                cfg.download_dir = self
+                    .download_dir
+                    .join(a.download_subdir.as_deref().unwrap_or(Path::new(name)));
+                cfg.state_dir = self.state_dir.as_ref().map(|d| d.join(name));
+                if let Some(enabled) = a.sync_enabled {
+                    cfg.sync_enabled = enabled;
+                }
+                if let Some(schedule) = &a.schedule {
+                    cfg.schedule = schedule.clone();
+                }
+                if let Some(filters) = &a.filters {
+                    cfg.filters = filters.clone();
+                }
+
+                cfg.resolve_credentials()
+                    .and_then(|()| cfg.schedule.validate())
+                    .and_then(|()| {
+                        crate::filters::Selection::compile(&cfg.filters).wrap_err("filters")
+                    })
+                    .wrap_err_with(|| format!("accounts: {name}"))?;
+                Ok((name.clone(), cfg))
+            })
+            .collect()
+    }
+
+    /// Storytel's login form of the password: either `password_encrypted`
+    /// or the encrypted cleartext `password`.
+    pub fn encrypted_password(&self) -> String {
+        self.password_encrypted
+            .clone()
+            .unwrap_or_else(|| crate::password_crypt::encrypt_password(self.password.trim()))
+    }
+
+    /// Reads `*_file` secrets and checks that exactly one way to authenticate is set.
+    fn resolve_credentials(&mut self) -> eyre::Result<()> {
+        for (name, value, file) in [
+            ("email", &mut self.email, &self.email_file),
+            ("password", &mut self.password, &self.password_file),
+        ] {
+            if let Some(file) = file {
+                eyre::ensure!(
+                    value.is_empty(),
+                    "set either `{name}` or `{name}_file`, not both"
+                );
+                let secret = std::fs::read_to_string(file)
+                    .wrap_err_with(|| format!("{name}_file: cannot read {}", file.display()))?;
+                *value = secret.trim_end_matches(['\r', '\n']).to_owned();
+            }
         }
+
+        let given = [
+            !self.password.is_empty(),
+            self.password_encrypted.is_some(),
+            self.token.is_some(),
+        ];
+        eyre::ensure!(
+            given.into_iter().filter(|g| *g).count() == 1,
+            "set exactly one of `password`, `password_file`, `password_encrypted` or `token`"
+        );
+        if let Some(hex) = &self.password_encrypted {
+            eyre::ensure!(
+                !hex.is_empty()
+                    && hex.len() % 32 == 0
+                    && hex.chars().all(|c| c.is_ascii_hexdigit()),
+                "`password_encrypted` must be the hex string printed by `encrypt-password`"
+            );
+        }
+        eyre::ensure!(
+            self.token.is_some() || !self.email.is_empty(),
+            "`email` or `email_file` is required"
+        );
+        Ok(())
+    }
+}
+
+/// Default config as JSON, with one account; its shape tells which keys
+/// hold strings.
+fn template() -> eyre::Result<Value> {
+    Ok(serde_json::to_value(serde_json::from_value::<Config>(
+        serde_json::json!({ "download_dir": "", "accounts": [{ "name": "" }] }),
+    )?)?)
+}
+
+/// Child `p` of a template node; every index of a list maps to its one entry.
+fn template_child<'a>(t: &'a mut Value, p: &str) -> Option<&'a mut Value> {
+    match t {
+        Value::Array(items) => items.first_mut().filter(|_| p.parse::<usize>().is_ok()),
+        Value::Object(table) => table.get_mut(p),
+        _ => None,
+    }
+}
+
+/// Whether the key at `parts` holds a string, judged by the default config
+/// in `template`. Unset optional keys are `null` there, so a string is tried
+/// in their place.
+fn takes_string(template: &Value, parts: &[&str]) -> bool {
+    let mut probe = template.clone();
+    let Some(slot) = parts
+        .iter()
+        .try_fold(&mut probe, |t, p| template_child(t, p))
+    else {
+        return false;
+    };
+    match slot {
+        Value::String(_) => true,
+        Value::Null => {
+            *slot = Value::String(String::new());
+            serde_json::from_value::<Config>(probe).is_ok()
+        }
+        _ => false,
+    }
+}
+
+/// Child `p` of `node`, created if missing: a list when `next` is an index,
+/// a table otherwise. In lists `p` is an index, at most one past the end.
+fn child<'a>(
+    node: &'a mut Value,
+    p: &str,
+    next: Option<&str>,
+    key: &str,
+) -> eyre::Result<&'a mut Value> {
+    let empty = || match next {
+        Some(n) if n.parse::<usize>().is_ok() => Value::Array(Vec::new()),
+        _ => Value::Object(Default::default()),
+    };
+    match node {
+        Value::Array(items) => {
+            let i: usize = p
+                .parse()
+                .map_err(|_| eyre::eyre!("`{key}`: `{p}` is not a list index"))?;
+            if i == items.len() {
+                items.push(empty());
+            }
+            let len = items.len();
+            items.get_mut(i).ok_or_else(|| {
+                eyre::eyre!("`{key}`: index {i} is past the end of the list ({len} entries)")
+            })
+        }
+        Value::Object(table) => Ok(table.entry(p).or_insert_with(empty)),
+        _ => eyre::bail!("`{key}`: `{p}` is not a table"),
+    }
+}
+
+/// Sets the dotted `key` of `doc` to `raw`; numeric parts index lists, as in
+/// `accounts.0.token`. Keys that hold strings, including optional ones, take
+/// `raw` verbatim; others parse it as JSON (numbers, booleans, lists, tables)
+/// and fall back to a string.
+fn set_key(doc: &mut Value, template: &Value, key: &str, raw: &str) -> eyre::Result<()> {
+    let parts: Vec<&str> = key.split('.').collect();
+    eyre::ensure!(
+        parts.iter().all(|p| !p.is_empty()),
+        "invalid config key `{key}`"
+    );
+    let value = if takes_string(template, &parts) {
+        Value::String(raw.to_owned())
+    } else {
+        serde_json::from_str(raw).unwrap_or_else(|_| Value::String(raw.to_owned()))
+    };
+
+    let (last, parents) = parts.split_last().unwrap();
+    let mut node = doc;
+    for (i, p) in parents.iter().enumerate() {
+        node = child(node, p, parts.get(i + 1).copied(), key)?;
+    }
+    // a secret set at higher precedence replaces its counterparts from below,
+    // at the top level and in account entries
+    const EMAIL: [&str; 2] = ["email", "email_file"];
+    const PASSWORD: [&str; 4] = ["password", "password_file", "password_encrypted", "token"];
+    if let Value::Object(table) = node
+        && (parents.is_empty() || matches!(parents, ["accounts", _]))
+    {
+        for group in [&EMAIL[..], &PASSWORD[..]] {
+            if group.contains(last) {
+                table.retain(|k, _| !group.contains(&k.as_str()));
+            }
+        }
+    }
+    *child(node, last, None, key)? = value;
+    Ok(())
+}
+
+/// Config of one account that a reload can replace while it is in use.
+/// Readers take a snapshot with [`Self::get`] and keep it for one operation.
+pub struct LiveConfig(RwLock<Arc<Config>>);
+
+impl LiveConfig {
+    pub fn new(cfg: Config) -> Self {
+        Self(RwLock::new(Arc::new(cfg)))
+    }
+
+    pub fn get(&self) -> Arc<Config> {
+        self.0.read().unwrap().clone()
+    }
+
+    pub fn set(&self, cfg: Config) {
+        *self.0.write().unwrap() = Arc::new(cfg);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn set(key: &str, raw: &str) -> Value {
+        let mut doc = json!({});
+        set_key(&mut doc, &template().unwrap(), key, raw).unwrap();
+        doc
+    }
+
+    #[test]
+    fn string_keys_are_taken_verbatim() {
+        assert_eq!(set("email", "12345"), json!({"email": "12345"}));
+        assert_eq!(set("token", "12345"), json!({"token": "12345"}));
+        assert_eq!(set("token", r#""quoted""#), json!({"token": r#""quoted""#}));
+        assert_eq!(
+            set("password_encrypted", "true"),
+            json!({"password_encrypted": "true"})
+        );
+        assert_eq!(set("state_dir", "123"), json!({"state_dir": "123"}));
+        assert_eq!(
+            set("schedule.cron", "0 3 * * *"),
+            json!({"schedule": {"cron": "0 3 * * *"}})
+        );
+        assert_eq!(
+            set("prune.archive_dir", "[1]"),
+            json!({"prune": {"archive_dir": "[1]"}})
+        );
+    }
+
+    #[test]
+    fn other_keys_parse_as_json() {
+        assert_eq!(set("sync_enabled", "true"), json!({"sync_enabled": true}));
+        assert_eq!(
+            set("storage.max_library_bytes", "100"),
+            json!({"storage": {"max_library_bytes": 100}})
+        );
+        assert_eq!(
+            set("filters.exclude_authors", r#"["Jane Doe"]"#),
+            json!({"filters": {"exclude_authors": ["Jane Doe"]}})
+        );
+        // not valid JSON, kept as a string for serde to judge
+        assert_eq!(
+            set("filters.added_within_days", "soon"),
+            json!({"filters": {"added_within_days": "soon"}})
+        );
+    }
+
+    #[test]
+    fn secrets_replace_their_counterparts() {
+        let mut doc = json!({"password": "a", "email": "me@example.com"});
+        set_key(&mut doc, &template().unwrap(), "token", "t").unwrap();
+        assert_eq!(doc, json!({"token": "t", "email": "me@example.com"}));
+    }
+
+    #[test]
+    fn account_entries_are_indexed() {
+        let template = template().unwrap();
+        let mut doc = json!({"accounts": [{"name": "anna", "password": "a"}]});
+        set_key(&mut doc, &template, "accounts.0.token", "12345").unwrap();
+        set_key(&mut doc, &template, "accounts.1.name", "bob").unwrap();
+        set_key(&mut doc, &template, "accounts.1.sync_enabled", "false").unwrap();
+        assert_eq!(
+            doc,
+            json!({"accounts": [
+                {"name": "anna", "token": "12345"},
+                {"name": "bob", "sync_enabled": false},
+            ]})
+        );
+        assert!(set_key(&mut doc, &template, "accounts.5.name", "eve").is_err());
+        assert!(set_key(&mut doc, &template, "accounts.x.name", "eve").is_err());
+        assert_eq!(
+            set("accounts.0.password_file", "/run/secrets/anna"),
+            json!({"accounts": [{"password_file": "/run/secrets/anna"}]})
+        );
+    }
+
+    #[test]
+    fn kubernetes_service_links_are_skipped() {
+        for var in [
+            "PORT",
+            "PORT_8080_TCP",
+            "PORT_8080_TCP_ADDR",
+            "SERVICE_HOST",
+            "SERVICE_PORT",
+            "SERVICE_PORT_HTTP",
+        ] {
+            assert!(is_service_link(var), "{var}");
+        }
+        for var in [
+            "SCHEDULE__CRON",
+            "PASSWORD_FILE",
+            "LISTEN_PORT",
+            "PRUNE__POLICY",
+        ] {
+            assert!(!is_service_link(var), "{var}");
+        }
+    }
+
+    #[test]
+    fn overrides_win_over_the_file() {
+        let path =
+            std::env::temp_dir().join(format!("storytel-sync-cfg-{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            "email = \"me@example.com\"\npassword = \"x\"\ndownload_dir = \"/tmp\"\n",
+        )
+        .unwrap();
+        let cfg = Config::load(
+            &path,
+            &["token=12345".into(), "schedule.jitter_secs=0".into()],
+        )
+        .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(cfg.token.as_deref(), Some("12345"));
+        assert!(cfg.password.is_empty());
+        assert_eq!(cfg.schedule.jitter_secs, Some(0));
     }
 }
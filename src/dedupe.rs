@@ -0,0 +1,50 @@
+use crate::filters::normalize_isbn;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// Books of other accounts' libraries by normalized ISBN, read from the
+/// `metadata.json` sidecars.
+pub fn isbn_index(dirs: &[PathBuf]) -> HashMap<String, PathBuf> {
+    let mut index = HashMap::new();
+    for dir in dirs {
+        let books = match crate::prune::scan_local(dir) {
+            Ok(books) => books,
+            Err(e) => {
+                tracing::warn!("dedupe: cannot scan {}: {e:#}", dir.display());
+                continue;
+            }
+        };
+        for book in books {
+            let isbn = std::fs::read_to_string(book.join("metadata.json"))
+                .ok()
+                .and_then(|s| serde_json::from_str::<serde_json::Value>(&s).ok())
+                .and_then(|v| v.get("isbn")?.as_str().map(normalize_isbn));
+            if let Some(isbn) = isbn.filter(|i| !i.is_empty()) {
+                index.entry(isbn).or_insert(book);
+            }
+        }
+    }
+    index
+}
+
+/// Hard-links every file of `src` into `target`, copying where linking is
+/// not possible (e.g. across file systems). Returns the audio file size.
+pub fn link_book(src: &Path, target: &Path) -> eyre::Result<u64> {
+    std::fs::create_dir_all(target)?;
+    let mut entries = std::fs::read_dir(src)?.collect::<Result<Vec<_>, _>>()?;
+    // the audio file marks a book as downloaded, so it goes last
+    entries.sort_by_key(|e| e.file_name() == "audio.mp3");
+    for entry in entries {
+        if !entry.file_type()?.is_file() {
+            continue;
+        }
+        let dst = target.join(entry.file_name());
+        if dst.exists() {
+            continue;
+        }
+        if std::fs::hard_link(entry.path(), &dst).is_err() {
+            std::fs::copy(entry.path(), &dst)?;
+        }
+    }
+    Ok(std::fs::metadata(target.join("audio.mp3"))?.len())
+}
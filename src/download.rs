@@ -4,15 +4,51 @@ type SenderType = ();
 #[allow(dead_code)]
 type ReceiverType = ();
 
+use crate::config::Retry;
+use actix_web::web;
 use futures_util::StreamExt;
+use std::collections::HashSet;
 use std::path::Path;
+use std::sync::Mutex;
 use tokio::{fs, io::AsyncWriteExt};
 
+/// Abook ids with a download in flight, shared by the sync worker and the
+/// on-demand download handler so a book is never written by two tasks.
+#[derive(Default)]
+pub struct BookLocks(Mutex<HashSet<u64>>);
+
+/// Releases the book id when dropped.
+pub struct BookLock {
+    locks: web::Data<BookLocks>,
+    id: u64,
+}
+
+impl BookLocks {
+    pub fn try_lock(locks: &web::Data<Self>, id: u64) -> Option<BookLock> {
+        locks.0.lock().unwrap().insert(id).then(|| BookLock {
+            locks: locks.clone(),
+            id,
+        })
+    }
+}
+
+impl Drop for BookLock {
+    fn drop(&mut self) {
+        self.locks.0.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// Makes an author or title usable as a single path component.
+pub fn sanitize(s: &str) -> String {
+    s.replace(['/', '\\'], "_")
+}
+
 pub fn is_downloaded(dst_dir: &Path, author: &str, title: &str) -> bool {
     dst_dir.join(author).join(title).join("audio.mp3").exists()
 }
 
-pub async fn download_cover(cover_url: &str, book_path: &Path) -> eyre::Result<()> {
+/// Saves the cover next to the audio unless it is already there.
+pub async fn download_cover(cover_url: &str, book_path: &Path, retry: &Retry) -> eyre::Result<()> {
     let ext = Path::new(cover_url)
         .extension()
         .and_then(|e| e.to_str())
@@ -23,7 +59,8 @@ pub async fn download_cover(cover_url: &str, book_path: &Path) -> eyre::Result<(
         return Ok(());
     }
 
-    let resp = reqwest::get(cover_url).await?;
+    let http = reqwest::Client::new();
+    let resp = crate::retry::send(retry, "download_cover", || http.get(cover_url)).await?;
     if !resp.status().is_success() {
         tracing::warn!("download_cover: request returned {}", resp.status());
         return Ok(());
@@ -0,0 +1,280 @@
+use crate::client_storytel_api::BookEntry;
+use crate::config::Filters;
+use chrono::{DateTime, Duration, Utc};
+use regex::{Regex, RegexBuilder};
+
+/// Skip reason of books `added_within_days` cannot judge.
+pub const ADD_DATE_UNKNOWN: &str = "add date unknown";
+
+/// Compiled form of [`Filters`], applied to every bookshelf entry during sync.
+pub struct Selection {
+    include_authors: Vec<Regex>,
+    exclude_authors: Vec<Regex>,
+    include_titles: Vec<Regex>,
+    exclude_titles: Vec<Regex>,
+    include_ids: Vec<u64>,
+    exclude_ids: Vec<u64>,
+    include_isbns: Vec<String>,
+    exclude_isbns: Vec<String>,
+    only_started: bool,
+    added_after: Option<DateTime<Utc>>,
+}
+
+/// Translates a glob (`*`, `?`, `[...]`) into an anchored regex.
+fn glob_to_regex(glob: &str) -> String {
+    let mut re = String::from("^");
+    let mut in_class = false;
+    for c in glob.chars() {
+        match c {
+            '*' if !in_class => re.push_str(".*"),
+            '?' if !in_class => re.push('.'),
+            '[' if !in_class => {
+                in_class = true;
+                re.push('[');
+            }
+            ']' if in_class => {
+                in_class = false;
+                re.push(']');
+            }
+            '!' if in_class && re.ends_with('[') => re.push('^'),
+            '-' if in_class => re.push('-'),
+            c => re.push_str(&regex::escape(c.encode_utf8(&mut [0; 4]))),
+        }
+    }
+    re.push('$');
+    re
+}
+
+fn compile_pattern(p: &str) -> eyre::Result<Regex> {
+    let re = match p.strip_prefix('/').and_then(|r| r.strip_suffix('/')) {
+        Some(re) => re.to_owned(),
+        None => glob_to_regex(p),
+    };
+    RegexBuilder::new(&re)
+        .case_insensitive(true)
+        .build()
+        .map_err(|e| eyre::eyre!("invalid pattern {p:?}: {e}"))
+}
+
+fn compile_all(patterns: &[String]) -> eyre::Result<Vec<Regex>> {
+    patterns.iter().map(|p| compile_pattern(p)).collect()
+}
+
+pub fn normalize_isbn(isbn: &str) -> String {
+    isbn.chars().filter(char::is_ascii_alphanumeric).collect()
+}
+
+fn isbns(be: &BookEntry) -> Vec<String> {
+    [
+        be.isbn.as_deref(),
+        be.book.isbn.as_deref(),
+        be.abook.as_ref().and_then(|a| a.isbn.as_deref()),
+    ]
+    .into_iter()
+    .flatten()
+    .map(normalize_isbn)
+    .collect()
+}
+
+impl Selection {
+    pub fn compile(f: &Filters) -> eyre::Result<Self> {
+        Ok(Self {
+            include_authors: compile_all(&f.include_authors)?,
+            exclude_authors: compile_all(&f.exclude_authors)?,
+            include_titles: compile_all(&f.include_titles)?,
+            exclude_titles: compile_all(&f.exclude_titles)?,
+            include_ids: f.include_ids.clone(),
+            exclude_ids: f.exclude_ids.clone(),
+            include_isbns: f.include_isbns.iter().map(|i| normalize_isbn(i)).collect(),
+            exclude_isbns: f.exclude_isbns.iter().map(|i| normalize_isbn(i)).collect(),
+            only_started: f.only_started,
+            added_after: f
+                .added_within_days
+                .map(|d| Utc::now() - Duration::days(i64::from(d))),
+        })
+    }
+
+    fn has_includes(&self) -> bool {
+        !(self.include_authors.is_empty()
+            && self.include_titles.is_empty()
+            && self.include_ids.is_empty()
+            && self.include_isbns.is_empty())
+    }
+
+    /// Returns why `be` is not selected, or `None` if it should be synced.
+    pub fn skip_reason(&self, be: &BookEntry) -> Option<String> {
+        let id = be.abook.as_ref().map(|a| a.id)?;
+        let author = be.book.authors_as_string.as_deref().unwrap_or("");
+        let title = be.book.name.as_str();
+        let isbns = isbns(be);
+
+        if self.exclude_ids.contains(&id) {
+            return Some("excluded by id".into());
+        }
+        if isbns.iter().any(|i| self.exclude_isbns.contains(i)) {
+            return Some("excluded by ISBN".into());
+        }
+        if self.exclude_authors.iter().any(|r| r.is_match(author)) {
+            return Some("excluded by author".into());
+        }
+        if self.exclude_titles.iter().any(|r| r.is_match(title)) {
+            return Some("excluded by title".into());
+        }
+        if self.has_includes()
+            && !(self.include_ids.contains(&id)
+                || isbns.iter().any(|i| self.include_isbns.contains(i))
+                || self.include_authors.iter().any(|r| r.is_match(author))
+                || self.include_titles.iter().any(|r| r.is_match(title)))
+        {
+            return Some("not matched by any include rule".into());
+        }
+        if self.only_started && be.abookmark.as_ref().is_none_or(|m| m.position <= 0) {
+            return Some("not started".into());
+        }
+        if let Some(after) = self.added_after {
+            // without a date the book cannot be shown to be recent
+            match be.insert_date {
+                None => return Some(ADD_DATE_UNKNOWN.into()),
+                Some(added) if added < after => {
+                    return Some(format!("added {}", added.format("%Y-%m-%d")));
+                }
+                Some(_) => {}
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn entry(id: u64, author: &str, title: &str, extra: serde_json::Value) -> BookEntry {
+        let mut be = json!({
+            "abook": {"id": id, "isbn": "978-91-000-0000-0"},
+            "book": {"name": title, "authorsAsString": author},
+        });
+        be.as_object_mut()
+            .unwrap()
+            .extend(extra.as_object().unwrap().clone());
+        serde_json::from_value(be).unwrap()
+    }
+
+    fn book(id: u64, author: &str, title: &str) -> BookEntry {
+        entry(id, author, title, json!({}))
+    }
+
+    #[test]
+    fn globs() {
+        let matches = |p: &str, s: &str| compile_pattern(p).unwrap().is_match(s);
+        assert!(matches("*tolkien*", "J.R.R. Tolkien"));
+        assert!(matches("Terry Pratchett", "terry pratchett"));
+        assert!(!matches("Terry", "Terry Pratchett"));
+        assert!(matches("Part ?", "Part 2"));
+        assert!(!matches("Part ?", "Part 12"));
+        assert!(matches("Part [1-3]", "Part 2"));
+        assert!(!matches("Part [!1-3]", "Part 2"));
+        assert!(matches("a.b (c)+", "A.B (C)+"));
+        assert!(!matches("a.b", "axb"));
+    }
+
+    #[test]
+    fn regexes() {
+        let re = compile_pattern("/sample|preview/").unwrap();
+        assert!(re.is_match("Mort (Preview)"));
+        assert!(!re.is_match("Mort"));
+        assert!(compile_pattern("/(unclosed/").is_err());
+    }
+
+    #[test]
+    fn excludes_win_over_includes() {
+        let selection = Selection::compile(&Filters {
+            include_authors: vec!["Terry*".into()],
+            exclude_titles: vec!["/sample/".into()],
+            exclude_isbns: vec!["9789100000001".into()],
+            ..Filters::default()
+        })
+        .unwrap();
+        assert_eq!(
+            selection.skip_reason(&book(1, "Terry Pratchett", "Mort")),
+            None
+        );
+        assert_eq!(
+            selection
+                .skip_reason(&book(2, "Terry Pratchett", "Mort sample"))
+                .as_deref(),
+            Some("excluded by title")
+        );
+        assert_eq!(
+            selection
+                .skip_reason(&book(3, "Jane Doe", "Mort"))
+                .as_deref(),
+            Some("not matched by any include rule")
+        );
+        let other_isbn = entry(
+            4,
+            "Terry Pratchett",
+            "Eric",
+            json!({"isbn": "978-91-000-0000-1"}),
+        );
+        assert_eq!(
+            selection.skip_reason(&other_isbn).as_deref(),
+            Some("excluded by ISBN")
+        );
+    }
+
+    #[test]
+    fn ids_and_isbns_are_includes() {
+        let selection = Selection::compile(&Filters {
+            include_ids: vec![7],
+            include_isbns: vec!["9789100000000".into()],
+            ..Filters::default()
+        })
+        .unwrap();
+        assert_eq!(selection.skip_reason(&book(7, "A", "B")), None);
+        // every test book carries the included ISBN
+        assert_eq!(selection.skip_reason(&book(8, "A", "B")), None);
+    }
+
+    #[test]
+    fn only_started() {
+        let selection = Selection::compile(&Filters {
+            only_started: true,
+            ..Filters::default()
+        })
+        .unwrap();
+        let started = entry(1, "A", "B", json!({"abookMark": {"bookId": 1, "pos": 5}}));
+        assert_eq!(selection.skip_reason(&started), None);
+        assert_eq!(
+            selection.skip_reason(&book(2, "A", "B")).as_deref(),
+            Some("not started")
+        );
+    }
+
+    #[test]
+    fn added_within_days() {
+        let selection = Selection::compile(&Filters {
+            added_within_days: Some(30),
+            ..Filters::default()
+        })
+        .unwrap();
+        let recent = Utc::now() - Duration::days(2);
+        let fresh = entry(
+            1,
+            "A",
+            "B",
+            json!({"insertDate": recent.timestamp_millis()}),
+        );
+        assert_eq!(selection.skip_reason(&fresh), None);
+        let old = entry(2, "A", "B", json!({"insertDate": "2020-01-02"}));
+        assert_eq!(
+            selection.skip_reason(&old).as_deref(),
+            Some("added 2020-01-02")
+        );
+        assert_eq!(
+            selection.skip_reason(&book(3, "A", "B")).as_deref(),
+            Some(ADD_DATE_UNKNOWN)
+        );
+    }
+}
@@ -0,0 +1,135 @@
+use serde::Serialize;
+use std::collections::BTreeSet;
+use std::path::Path;
+use std::sync::Mutex;
+
+/// Liveness/readiness inputs updated by login and the sync worker.
+pub struct Health {
+    /// Accounts whose last login failed.
+    login_failed: Mutex<BTreeSet<String>>,
+    /// Accounts whose last sync pass failed.
+    sync_failed: Mutex<BTreeSet<String>>,
+}
+
+pub static HEALTH: Health = Health {
+    login_failed: Mutex::new(BTreeSet::new()),
+    sync_failed: Mutex::new(BTreeSet::new()),
+};
+
+#[derive(Serialize)]
+pub struct Readiness {
+    pub ready: bool,
+    /// The last login of every account succeeded.
+    pub login_ok: bool,
+    /// Every account's download directory is writable.
+    pub download_dir_writable: bool,
+    /// The last sync pass of at least one account failed.
+    pub last_sync_failed: bool,
+    /// Accounts with a failed login, an unwritable download directory or a
+    /// failed last pass.
+    pub failing_accounts: Vec<String>,
+}
+
+fn is_writable(dir: &Path) -> bool {
+    let probe = dir.join(".storytel-sync-probe");
+    let ok = std::fs::write(&probe, b"").is_ok();
+    let _ = std::fs::remove_file(&probe);
+    ok
+}
+
+fn set_flag(accounts: &Mutex<BTreeSet<String>>, account: &str, on: bool) {
+    let mut accounts = accounts.lock().unwrap();
+    if on {
+        accounts.insert(account.to_owned());
+    } else {
+        accounts.remove(account);
+    }
+}
+
+impl Health {
+    pub fn set_login_failed(&self, account: &str, failed: bool) {
+        set_flag(&self.login_failed, account, failed);
+    }
+
+    pub fn set_sync_failed(&self, account: &str, failed: bool) {
+        set_flag(&self.sync_failed, account, failed);
+    }
+
+    /// Readiness over all accounts, given as `(name, download_dir)` pairs.
+    pub fn readiness<'a>(
+        &self,
+        accounts: impl IntoIterator<Item = (&'a str, &'a Path)>,
+    ) -> Readiness {
+        let login_failed = self.login_failed.lock().unwrap();
+        let sync_failed = self.sync_failed.lock().unwrap();
+        let (mut login_ok, mut download_dir_writable, mut last_sync_failed) = (true, true, false);
+        let mut failing_accounts = Vec::new();
+        for (name, dir) in accounts {
+            let logged_in = !login_failed.contains(name);
+            let writable = is_writable(dir);
+            let failed = sync_failed.contains(name);
+            login_ok &= logged_in;
+            download_dir_writable &= writable;
+            last_sync_failed |= failed;
+            if !logged_in || !writable || failed {
+                failing_accounts.push(name.to_owned());
+            }
+        }
+        Readiness {
+            ready: login_ok && download_dir_writable && !last_sync_failed,
+            login_ok,
+            download_dir_writable,
+            last_sync_failed,
+            failing_accounts,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn one_failed_account_fails_readiness() {
+        let health = Health {
+            login_failed: Mutex::new(BTreeSet::new()),
+            sync_failed: Mutex::new(BTreeSet::new()),
+        };
+        let dir = std::env::temp_dir();
+        let missing = dir.join(format!("storytel-sync-health-{}", std::process::id()));
+        let accounts = || [("anna", dir.as_path()), ("bob", dir.as_path())];
+
+        assert!(health.readiness(accounts()).ready);
+        health.set_sync_failed("anna", true);
+        health.set_sync_failed("bob", false);
+        let r = health.readiness(accounts());
+        assert!(!r.ready && r.last_sync_failed);
+        assert_eq!(r.failing_accounts, ["anna"]);
+
+        health.set_sync_failed("anna", false);
+        let r = health.readiness([("anna", dir.as_path()), ("bob", missing.as_path())]);
+        assert!(!r.ready && !r.download_dir_writable && !r.last_sync_failed);
+        assert_eq!(r.failing_accounts, ["bob"]);
+    }
+
+    #[test]
+    fn login_state_is_tracked_per_account() {
+        let health = Health {
+            login_failed: Mutex::new(BTreeSet::new()),
+            sync_failed: Mutex::new(BTreeSet::new()),
+        };
+        let dir = std::env::temp_dir();
+        let accounts = || [("anna", dir.as_path()), ("bob", dir.as_path())];
+
+        health.set_login_failed("bob", true);
+        let r = health.readiness(accounts());
+        assert!(!r.ready && !r.login_ok);
+        assert_eq!(r.failing_accounts, ["bob"]);
+        // a later relogin fails for anna and the retry succeeds for bob
+        health.set_login_failed("anna", true);
+        health.set_login_failed("bob", false);
+        assert_eq!(health.readiness(accounts()).failing_accounts, ["anna"]);
+        health.set_login_failed("anna", false);
+        assert!(health.readiness(accounts()).ready);
+    }
+}
@@ -0,0 +1,214 @@
+use actix_web::http::{StatusCode, header};
+use actix_web::{HttpRequest, HttpResponse, web};
+use percent_encoding::{AsciiSet, CONTROLS, utf8_percent_encode};
+use std::path::{Component, Path, PathBuf};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+/// Characters escaped in the path of `/files/...` links.
+const PATH_ESCAPE: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
+
+/// Downloaded book found on disk, described by its `metadata.json`.
+pub struct LocalBook {
+    /// Book directory relative to `download_dir` (`author/title`).
+    pub dir: String,
+    pub author: String,
+    pub title: String,
+    pub isbn: Option<String>,
+    /// File name of the downloaded cover, if any.
+    pub cover: Option<String>,
+}
+
+/// Every downloaded book under `dl_dir`, sorted by author and title. Used
+/// when the bookshelf cannot be fetched from Storytel.
+pub fn scan(dl_dir: &Path) -> eyre::Result<Vec<LocalBook>> {
+    let mut books = Vec::new();
+    for book_dir in crate::prune::scan_local(dl_dir)? {
+        let dir = crate::state::book_key(dl_dir, &book_dir);
+        let meta = std::fs::read_to_string(book_dir.join("metadata.json"))
+            .ok()
+            .and_then(|s| serde_json::from_str::<serde_json::Value>(&s).ok())
+            .unwrap_or_default();
+        let text = |key: &str| meta.get(key).and_then(|v| v.as_str()).map(str::to_owned);
+        let authors: Vec<&str> = meta
+            .get("authors")
+            .and_then(|v| v.as_array())
+            .map(|a| a.iter().filter_map(|v| v.as_str()).collect())
+            .unwrap_or_default();
+        let name = |i: usize| {
+            let mut parts = dir.rsplit(std::path::MAIN_SEPARATOR);
+            parts.nth(i).unwrap_or_default().to_owned()
+        };
+        let cover = std::fs::read_dir(&book_dir)?
+            .filter_map(Result::ok)
+            .map(|e| e.file_name().to_string_lossy().into_owned())
+            .find(|n| n.starts_with("cover."));
+        books.push(LocalBook {
+            author: if authors.is_empty() {
+                name(1)
+            } else {
+                authors.join(", ")
+            },
+            title: text("title").unwrap_or_else(|| name(0)),
+            isbn: text("isbn"),
+            cover,
+            dir,
+        });
+    }
+    books.sort_by(|a, b| (&a.author, &a.title).cmp(&(&b.author, &b.title)));
+    Ok(books)
+}
+
+/// Link to `file` in the book directory `dir`, served by [`serve`].
+pub fn file_url(dir: &str, file: &str, query: &str) -> String {
+    let dir = dir.replace(std::path::MAIN_SEPARATOR, "/");
+    format!(
+        "/files/{}/{}{query}",
+        utf8_percent_encode(&dir, PATH_ESCAPE),
+        utf8_percent_encode(file, PATH_ESCAPE)
+    )
+}
+
+/// Resolves `rel` under `dl_dir`, refusing parent directories and hidden
+/// entries such as the state directory with the session token.
+fn resolve(dl_dir: &Path, rel: &str) -> Option<PathBuf> {
+    let rel = Path::new(rel);
+    let safe = rel.components().all(|c| match c {
+        Component::Normal(name) => !name.to_string_lossy().starts_with('.'),
+        _ => false,
+    });
+    if !safe || rel.as_os_str().is_empty() {
+        return None;
+    }
+    let path = dl_dir.join(rel).canonicalize().ok()?;
+    let root = dl_dir.canonicalize().ok()?;
+    (path.starts_with(&root) && path.is_file()).then_some(path)
+}
+
+fn content_type(path: &Path) -> &'static str {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("mp3") => "audio/mpeg",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("png") => "image/png",
+        Some("webp") => "image/webp",
+        Some("json") => "application/json",
+        Some("opf") => "application/oebps-package+xml",
+        Some("txt") => "text/plain; charset=utf-8",
+        _ => "application/octet-stream",
+    }
+}
+
+/// First range of a `Range: bytes=...` header as an inclusive byte span.
+/// `Err` means the range cannot be satisfied.
+fn parse_range(value: &str, len: u64) -> Result<Option<(u64, u64)>, ()> {
+    let Some(spec) = value.strip_prefix("bytes=") else {
+        return Ok(None);
+    };
+    let spec = spec.split(',').next().unwrap_or_default().trim();
+    let Some((start, end)) = spec.split_once('-') else {
+        return Ok(None);
+    };
+    let (start, end) = match (start.parse::<u64>(), end.parse::<u64>()) {
+        (Ok(start), Ok(end)) => (start, end.min(len.saturating_sub(1))),
+        (Ok(start), Err(_)) if end.is_empty() => (start, len.saturating_sub(1)),
+        (Err(_), Ok(suffix)) if start.is_empty() && suffix > 0 => {
+            (len.saturating_sub(suffix), len.saturating_sub(1))
+        }
+        _ => return Ok(None),
+    };
+    if start >= len || start > end {
+        return Err(());
+    }
+    Ok(Some((start, end)))
+}
+
+/// Streams a file of the local library, honouring `Range` requests so
+/// players can seek.
+pub async fn serve(req: &HttpRequest, dl_dir: &Path, rel: &str) -> HttpResponse {
+    let Some(path) = resolve(dl_dir, rel) else {
+        return HttpResponse::NotFound().body("no such file");
+    };
+    let res = async {
+        let mut file = tokio::fs::File::open(&path).await?;
+        let len = file.metadata().await?.len();
+        let range = req
+            .headers()
+            .get(header::RANGE)
+            .and_then(|v| v.to_str().ok())
+            .map_or(Ok(None), |v| parse_range(v, len));
+        let (status, start, end) = match range {
+            Ok(Some((start, end))) => (StatusCode::PARTIAL_CONTENT, start, end),
+            Ok(None) => (StatusCode::OK, 0, len.saturating_sub(1)),
+            Err(()) => {
+                return Ok(HttpResponse::RangeNotSatisfiable()
+                    .insert_header((header::CONTENT_RANGE, format!("bytes */{len}")))
+                    .finish());
+            }
+        };
+        file.seek(std::io::SeekFrom::Start(start)).await?;
+        let size = if len == 0 { 0 } else { end - start + 1 };
+
+        let body = futures_util::stream::unfold((file, size), |(mut file, left)| async move {
+            if left == 0 {
+                return None;
+            }
+            let mut buf = vec![0; left.min(64 * 1024) as usize];
+            match file.read(&mut buf).await {
+                Ok(0) => None,
+                Ok(n) => {
+                    buf.truncate(n);
+                    Some((Ok(web::Bytes::from(buf)), (file, left - n as u64)))
+                }
+                Err(e) => Some((Err(e), (file, 0))),
+            }
+        });
+        let mut resp = HttpResponse::build(status);
+        resp.insert_header((header::CONTENT_TYPE, content_type(&path)))
+            .insert_header((header::ACCEPT_RANGES, "bytes"));
+        if status == StatusCode::PARTIAL_CONTENT {
+            resp.insert_header((header::CONTENT_RANGE, format!("bytes {start}-{end}/{len}")));
+        }
+        eyre::Ok(resp.no_chunking(size).streaming(body))
+    }
+    .await;
+    res.unwrap_or_else(|e| {
+        tracing::error!("files: cannot serve {}: {e:#}", path.display());
+        HttpResponse::InternalServerError().body(e.to_string())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ranges() {
+        assert_eq!(parse_range("bytes=0-99", 1000), Ok(Some((0, 99))));
+        assert_eq!(parse_range("bytes=500-", 1000), Ok(Some((500, 999))));
+        assert_eq!(parse_range("bytes=-100", 1000), Ok(Some((900, 999))));
+        // the end is clamped, only the first range is served
+        assert_eq!(parse_range("bytes=900-2000", 1000), Ok(Some((900, 999))));
+        assert_eq!(parse_range("bytes=0-9, 20-29", 1000), Ok(Some((0, 9))));
+        assert_eq!(parse_range("bytes=-5000", 1000), Ok(Some((0, 999))));
+    }
+
+    #[test]
+    fn unsatisfiable_and_ignored_ranges() {
+        assert_eq!(parse_range("bytes=1000-", 1000), Err(()));
+        assert_eq!(parse_range("bytes=50-10", 1000), Err(()));
+        assert_eq!(parse_range("bytes=0-", 0), Err(()));
+        // malformed headers are ignored and the whole file is sent
+        assert_eq!(parse_range("items=0-9", 1000), Ok(None));
+        assert_eq!(parse_range("bytes=abc", 1000), Ok(None));
+        assert_eq!(parse_range("bytes=-0", 1000), Ok(None));
+    }
+}
@@ -1,10 +1,32 @@
 mod client_storytel_api;
 mod config;
+mod dedupe;
 mod download;
+mod filters;
+mod health;
+mod library;
+mod media_server;
+mod metadata;
+mod metrics;
+mod mp3;
 mod password_crypt;
+mod prune;
+mod reload;
+mod retry;
+mod schedule;
+mod session;
+mod shelf_cache;
+mod shutdown;
+mod state;
+mod storage;
+mod sync;
+mod throttle;
+mod verify;
 mod web_app;
+mod webhooks;
 
-use std::path::Path;
+use eyre::WrapErr;
+use std::path::{Path, PathBuf};
 
 #[tokio::main]
 async fn main() -> eyre::Result<()> {
@@ -14,16 +36,11 @@ async fn main() -> eyre::Result<()> {
         .user_agent("okhttp/3.12.8")
         .redirect(reqwest::redirect::Policy::none())
         .build()?;
-    let login_data = client_storytel_api::Login {
-        account_info: client_storytel_api::AccountInfo {
-            single_sign_token: String::new(),
-        },
-    };
-
     let args = clap::Command::new("storytel")
         .arg(
             clap::Arg::new("config")
                 .long("config")
+                .env("STORYTEL_SYNC_CONFIG")
                 .required(true)
                 .value_name("FILE")
                 .num_args(1),
@@ -31,6 +48,7 @@ async fn main() -> eyre::Result<()> {
         .arg(
             clap::Arg::new("host")
                 .long("host")
+                .env("STORYTEL_SYNC_LISTEN_HOST")
                 .value_name("HOST")
                 .num_args(1)
                 .default_value("127.0.0.1"),
@@ -38,32 +56,179 @@ async fn main() -> eyre::Result<()> {
         .arg(
             clap::Arg::new("port")
                 .long("port")
+                .env("STORYTEL_SYNC_LISTEN_PORT")
                 .value_name("PORT")
                 .value_parser(clap::value_parser!(u16))
                 .num_args(1)
                 .default_value("8080"),
         )
+        .arg(
+            clap::Arg::new("set")
+                .long("set")
+                .value_name("KEY=VALUE")
+                .help("Override a config key, e.g. schedule.cron=\"0 3 * * *\"")
+                .action(clap::ArgAction::Append)
+                .global(true),
+        )
+        .subcommand_negates_reqs(true)
+        .subcommand(
+            clap::Command::new("encrypt-password")
+                .about("Read the Storytel password from stdin and print it encrypted, then exit")
+                .arg(
+                    clap::Arg::new("token")
+                        .long("token")
+                        .value_name("EMAIL")
+                        .help("Log in as EMAIL and print the single-sign token instead"),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("dry-run")
+                .about("Show what a sync pass would download or skip, then exit"),
+        )
+        .subcommand(
+            clap::Command::new("verify")
+                .about("Check every downloaded book for corrupt audio, then exit"),
+        )
         .get_matches();
 
-    let cfg_path = args.get_one::<String>("config").unwrap();
+    if let Some(sub) = args.subcommand_matches("encrypt-password") {
+        return encrypt_password(client, sub.get_one::<String>("token")).await;
+    }
+
+    let cfg_path = args
+        .get_one::<String>("config")
+        .ok_or_else(|| eyre::eyre!("--config is required"))?;
     let host = args.get_one::<String>("host").unwrap();
     let port = *args.get_one::<u16>("port").unwrap();
-    let app_cfg = config::Config::load(Path::new(cfg_path))?;
+    let overrides: Vec<String> = args
+        .get_many::<String>("set")
+        .unwrap_or_default()
+        .cloned()
+        .collect();
+    let app_cfg = config::Config::load(Path::new(cfg_path), &overrides)?;
+
+    if args.subcommand_matches("dry-run").is_some() {
+        return dry_run(client, &app_cfg).await;
+    }
 
-    let (sender, receiver) = (None, None);
+    // authenticate once so subsequent API calls have a token; on failure the
+    // local library is still served and login is retried in the background
+    let mut accounts = Vec::new();
+    for (name, cfg) in app_cfg.accounts()? {
+        // first, so a second process gives up before logging in
+        let store = state::StateStore::open(&cfg.state_dir())?;
+        let mut api = client_storytel_api::StorytelClient::for_account(client.clone(), &name, &cfg);
+        if let Err(e) = api.authenticate(&cfg).await {
+            tracing::error!("login failed for account {name}: {e:#}");
+        }
+        accounts.push(web_app::AccountInit {
+            name,
+            cfg,
+            client: api,
+            store,
+        });
+    }
+    let multi = accounts.len() > 1;
 
-    let mut client_data = client_storytel_api::ClientData {
-        request_client: client,
-        login_data,
-        sender,
-        receiver,
-        current_abookmark_id: None,
-        current_abook_id: None,
-        current_book_name: None,
+    if args.subcommand_matches("verify").is_some() {
+        let (mut checked, mut corrupt) = (0, 0);
+        for account in accounts {
+            let dl_dir = &account.cfg.download_dir;
+            // book lengths come from the shelf; without a login only the
+            // frame structure is checked
+            let lengths = match account.client.get_bookshelf().await {
+                Ok(shelf) => verify::expected_lengths(&shelf, dl_dir),
+                Err(e) => {
+                    tracing::warn!(
+                        "verify: cannot fetch bookshelf of {}, skipping length checks: {e:#}",
+                        account.name
+                    );
+                    Default::default()
+                }
+            };
+            let prefix = if multi {
+                format!("{}: ", account.name)
+            } else {
+                String::new()
+            };
+            let report = verify::verify_library(dl_dir, &lengths, &account.store)?;
+            checked += report.len();
+            for (book, res) in &report {
+                match res {
+                    Ok(duration) => {
+                        println!("OK       {prefix}{book} ({}s)", duration.as_secs());
+                    }
+                    Err(e) => {
+                        corrupt += 1;
+                        println!("CORRUPT  {prefix}{book}: {e:#}");
+                    }
+                }
+            }
+        }
+        println!("\n{checked} books checked, {corrupt} corrupt");
+        return Ok(());
+    }
+    let source = web_app::ConfigSource {
+        path: cfg_path.into(),
+        overrides,
     };
+    web_app::run(accounts, app_cfg, source, host.as_str(), port).await;
+    Ok(())
+}
 
-    // authenticate once so subsequent API calls have a token
-    client_storytel_api::login(&mut client_data, &app_cfg.email, &app_cfg.password).await?;
-    web_app::run(client_data, &app_cfg, host.as_str(), port).await;
+/// `encrypt-password` command: prints the value for `password_encrypted`, or
+/// for `token` when an email is given.
+/// Prints the plan of the next sync pass of every account. Unlike the server
+/// it writes nothing: the session is not saved, the state is only read and
+/// failed logins are not reported.
+async fn dry_run(client: reqwest::Client, app_cfg: &config::Config) -> eyre::Result<()> {
+    let throttles = throttle::Throttles::new(&app_cfg.bandwidth)?;
+    let accounts = app_cfg.accounts()?;
+    let dirs: Vec<PathBuf> = accounts
+        .iter()
+        .map(|(_, cfg)| cfg.download_dir.clone())
+        .collect();
+    for (name, cfg) in &accounts {
+        if accounts.len() > 1 {
+            println!("== {name} ==");
+        }
+        let mut api = client_storytel_api::StorytelClient::read_only(client.clone(), cfg);
+        api.authenticate(cfg)
+            .await
+            .wrap_err_with(|| format!("cannot plan a sync of {name} without a login"))?;
+        let dedupe_dirs = app_cfg.dedupe_dirs(&dirs, &cfg.download_dir);
+        let shelf = api.get_bookshelf().await?;
+        let plan = sync::dry_run(
+            &api,
+            &shelf,
+            cfg,
+            &state::load(&cfg.state_dir())?,
+            &dedupe_dirs,
+            Some(&throttles.sync),
+        )
+        .await?;
+        print!("{}", plan.to_text());
+    }
+    Ok(())
+}
+
+async fn encrypt_password(client: reqwest::Client, token_for: Option<&String>) -> eyre::Result<()> {
+    use std::io::{BufRead, IsTerminal, Write};
+
+    if std::io::stdin().is_terminal() {
+        eprint!("Storytel password: ");
+        std::io::stderr().flush()?;
+    }
+    let mut password = String::new();
+    std::io::stdin().lock().read_line(&mut password)?;
+    let encrypted = password_crypt::encrypt_password(password.trim());
+
+    let Some(email) = token_for else {
+        println!("{encrypted}");
+        return Ok(());
+    };
+    let api = client_storytel_api::StorytelClient::new(client, config::Retry::default());
+    api.login(email, &encrypted).await?;
+    println!("{}", api.token());
     Ok(())
 }
@@ -0,0 +1,126 @@
+use crate::config::MediaServer;
+use std::time::Duration;
+
+async fn trigger_scan(client: &reqwest::Client, server: &MediaServer) -> eyre::Result<()> {
+    let req = match server {
+        MediaServer::Audiobookshelf {
+            url,
+            token,
+            library_id,
+        } => client
+            .post(format!(
+                "{}/api/libraries/{library_id}/scan",
+                url.trim_end_matches('/')
+            ))
+            .bearer_auth(token),
+        MediaServer::Jellyfin { url, token } => client
+            .post(format!("{}/Library/Refresh", url.trim_end_matches('/')))
+            .header("X-Emby-Token", token),
+    };
+    let resp = req.send().await?;
+    if !resp.status().is_success() {
+        eyre::bail!("scan request returned {}", resp.status());
+    }
+    Ok(())
+}
+
+/// Asks every configured media server to rescan its library.
+/// Failures are logged and otherwise ignored.
+pub async fn notify_all(servers: &[MediaServer]) {
+    if servers.is_empty() {
+        return;
+    }
+    let client = match reqwest::Client::builder()
+        .timeout(Duration::from_secs(30))
+        .build()
+    {
+        Ok(c) => c,
+        Err(e) => {
+            tracing::warn!("media_server: cannot build client: {e}");
+            return;
+        }
+    };
+    for server in servers {
+        let name = match server {
+            MediaServer::Audiobookshelf { url, .. } => format!("audiobookshelf {url}"),
+            MediaServer::Jellyfin { url, .. } => format!("jellyfin {url}"),
+        };
+        match trigger_scan(&client, server).await {
+            Ok(()) => tracing::info!("media_server: triggered library scan on {name}"),
+            Err(e) => tracing::warn!("media_server: library scan on {name} failed: {e}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Answers one request with `status` and returns its head.
+    async fn stub_server(status: u16) -> (String, tokio::task::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let handle = tokio::spawn(async move {
+            let (mut sock, _) = listener.accept().await.unwrap();
+            let mut buf = Vec::new();
+            while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+                let mut chunk = [0; 1024];
+                let n = sock.read(&mut chunk).await.unwrap();
+                if n == 0 {
+                    break;
+                }
+                buf.extend_from_slice(&chunk[..n]);
+            }
+            let resp = format!("HTTP/1.1 {status} X\r\ncontent-length: 0\r\n\r\n");
+            sock.write_all(resp.as_bytes()).await.unwrap();
+            String::from_utf8(buf).unwrap().to_lowercase()
+        });
+        (url, handle)
+    }
+
+    #[tokio::test]
+    async fn audiobookshelf_scan() {
+        let (url, server) = stub_server(200).await;
+        let abs = MediaServer::Audiobookshelf {
+            url,
+            token: "secret".into(),
+            library_id: "lib1".into(),
+        };
+        trigger_scan(&reqwest::Client::new(), &abs).await.unwrap();
+        let req = server.await.unwrap();
+        assert!(req.starts_with("post /api/libraries/lib1/scan "), "{req}");
+        assert!(req.contains("authorization: bearer secret"), "{req}");
+    }
+
+    #[tokio::test]
+    async fn jellyfin_scan() {
+        let (url, server) = stub_server(204).await;
+        let jellyfin = MediaServer::Jellyfin {
+            url,
+            token: "secret".into(),
+        };
+        trigger_scan(&reqwest::Client::new(), &jellyfin)
+            .await
+            .unwrap();
+        let req = server.await.unwrap();
+        assert!(req.starts_with("post /library/refresh "), "{req}");
+        assert!(req.contains("x-emby-token: secret"), "{req}");
+    }
+
+    #[tokio::test]
+    async fn failed_scan_is_an_error() {
+        let (url, server) = stub_server(401).await;
+        let jellyfin = MediaServer::Jellyfin {
+            url,
+            token: "wrong".into(),
+        };
+        assert!(
+            trigger_scan(&reqwest::Client::new(), &jellyfin)
+                .await
+                .is_err()
+        );
+        server.await.unwrap();
+    }
+}
@@ -0,0 +1,253 @@
+use crate::client_storytel_api::BookEntry;
+use serde::Serialize;
+use std::fmt::Write;
+use std::path::Path;
+use tokio::fs;
+
+/// Book details written next to `audio.mp3` so self-hosted audiobook servers
+/// (Audiobookshelf, Plex, Jellyfin) can pick them up without online scraping.
+#[derive(Clone)]
+pub struct BookMetadata {
+    /// Storytel audiobook id, the OPF identifier of books without an ISBN.
+    pub abook_id: u64,
+    pub title: String,
+    pub authors: Vec<String>,
+    pub narrators: Vec<String>,
+    pub isbn: Option<String>,
+    pub description: Option<String>,
+    /// Length in milliseconds as reported by Storytel.
+    pub length: Option<u64>,
+}
+
+/// Audiobookshelf `metadata.json` schema.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct AbsMetadata<'a> {
+    tags: [&'a str; 0],
+    chapters: [&'a str; 0],
+    title: &'a str,
+    subtitle: Option<&'a str>,
+    authors: &'a [String],
+    narrators: &'a [String],
+    series: [&'a str; 0],
+    genres: [&'a str; 0],
+    published_year: Option<&'a str>,
+    published_date: Option<&'a str>,
+    publisher: Option<&'a str>,
+    description: Option<&'a str>,
+    isbn: Option<&'a str>,
+    asin: Option<&'a str>,
+    language: Option<&'a str>,
+    /// Length in seconds.
+    duration: Option<f64>,
+    explicit: bool,
+    abridged: bool,
+}
+
+fn split_names(s: Option<&str>) -> Vec<String> {
+    s.map(|s| {
+        s.split(',')
+            .map(str::trim)
+            .filter(|n| !n.is_empty())
+            .map(str::to_owned)
+            .collect()
+    })
+    .unwrap_or_default()
+}
+
+fn xml_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&apos;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// `H:MM:SS` for a length in milliseconds.
+fn format_length(ms: u64) -> String {
+    let secs = ms / 1000;
+    format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+impl BookMetadata {
+    pub fn from_entry(abook_id: u64, be: &BookEntry) -> Self {
+        Self {
+            abook_id,
+            title: be.book.name.clone(),
+            authors: split_names(
+                be.book
+                    .authors_as_string
+                    .as_deref()
+                    .or(be.author.as_deref()),
+            ),
+            narrators: split_names(
+                be.abook
+                    .as_ref()
+                    .and_then(|a| a.narrator_as_string.as_deref()),
+            ),
+            isbn: be
+                .abook
+                .as_ref()
+                .and_then(|a| a.isbn.clone())
+                .or_else(|| be.isbn.clone())
+                .or_else(|| be.book.isbn.clone()),
+            description: be
+                .description
+                .clone()
+                .or_else(|| be.book.description.clone()),
+            length: be.length.or(be.book.length),
+        }
+    }
+
+    fn to_abs_json(&self) -> eyre::Result<String> {
+        Ok(serde_json::to_string_pretty(&AbsMetadata {
+            tags: [],
+            chapters: [],
+            title: &self.title,
+            subtitle: None,
+            authors: &self.authors,
+            narrators: &self.narrators,
+            series: [],
+            genres: [],
+            published_year: None,
+            published_date: None,
+            publisher: None,
+            description: self.description.as_deref(),
+            isbn: self.isbn.as_deref(),
+            asin: None,
+            language: None,
+            duration: self.length.map(|ms| ms as f64 / 1000.0),
+            explicit: false,
+            abridged: false,
+        })?)
+    }
+
+    fn to_opf(&self) -> String {
+        let mut opf = String::from(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<package xmlns="http://www.idpf.org/2007/opf" version="2.0" unique-identifier="BookId">
+  <metadata xmlns:dc="http://purl.org/dc/elements/1.1/" xmlns:opf="http://www.idpf.org/2007/opf">
+"#,
+        );
+        writeln!(opf, "    <dc:title>{}</dc:title>", xml_escape(&self.title)).unwrap();
+        for author in &self.authors {
+            writeln!(
+                opf,
+                r#"    <dc:creator opf:role="aut">{}</dc:creator>"#,
+                xml_escape(author)
+            )
+            .unwrap();
+        }
+        for narrator in &self.narrators {
+            writeln!(
+                opf,
+                r#"    <dc:contributor opf:role="nrt">{}</dc:contributor>"#,
+                xml_escape(narrator)
+            )
+            .unwrap();
+        }
+        // the package's unique-identifier must always resolve
+        match &self.isbn {
+            Some(isbn) => writeln!(
+                opf,
+                r#"    <dc:identifier id="BookId" opf:scheme="ISBN">{}</dc:identifier>"#,
+                xml_escape(isbn)
+            ),
+            None => writeln!(
+                opf,
+                r#"    <dc:identifier id="BookId">storytel:{}</dc:identifier>"#,
+                self.abook_id
+            ),
+        }
+        .unwrap();
+        if let Some(desc) = &self.description {
+            writeln!(
+                opf,
+                "    <dc:description>{}</dc:description>",
+                xml_escape(desc)
+            )
+            .unwrap();
+        }
+        if let Some(ms) = self.length {
+            writeln!(
+                opf,
+                r#"    <meta name="duration" content="{}"/>"#,
+                format_length(ms)
+            )
+            .unwrap();
+        }
+        opf.push_str("  </metadata>\n</package>\n");
+        opf
+    }
+}
+
+async fn write_if_missing(target: &Path, contents: &str) -> eyre::Result<()> {
+    if target.exists() {
+        return Ok(());
+    }
+    fs::write(target, contents).await?;
+    Ok(())
+}
+
+/// Writes `metadata.json`, `metadata.opf`, `desc.txt` and `reader.txt`.
+/// Existing files are left untouched so manual edits survive a re-sync.
+pub async fn write_sidecars(meta: &BookMetadata, book_path: &Path) -> eyre::Result<()> {
+    fs::create_dir_all(book_path).await?;
+    write_if_missing(&book_path.join("metadata.json"), &meta.to_abs_json()?).await?;
+    write_if_missing(&book_path.join("metadata.opf"), &meta.to_opf()).await?;
+    if let Some(desc) = &meta.description {
+        write_if_missing(&book_path.join("desc.txt"), desc).await?;
+    }
+    if !meta.narrators.is_empty() {
+        write_if_missing(&book_path.join("reader.txt"), &meta.narrators.join(", ")).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn book(isbn: Option<&str>) -> BookMetadata {
+        BookMetadata {
+            abook_id: 42,
+            title: "Mort & Co".into(),
+            authors: vec!["Terry Pratchett".into()],
+            narrators: Vec::new(),
+            isbn: isbn.map(str::to_owned),
+            description: None,
+            length: Some(3_723_500),
+        }
+    }
+
+    #[test]
+    fn opf_identifier_falls_back_to_storytel_id() {
+        let opf = book(None).to_opf();
+        assert!(opf.contains(r#"unique-identifier="BookId""#));
+        assert!(opf.contains(r#"<dc:identifier id="BookId">storytel:42</dc:identifier>"#));
+
+        let opf = book(Some("9789100000000")).to_opf();
+        assert!(opf.contains(
+            r#"<dc:identifier id="BookId" opf:scheme="ISBN">9789100000000</dc:identifier>"#
+        ));
+        assert!(opf.contains("<dc:title>Mort &amp; Co</dc:title>"));
+    }
+
+    #[test]
+    fn length_is_written() {
+        let meta = book(None);
+        assert!(
+            meta.to_opf()
+                .contains(r#"<meta name="duration" content="1:02:03"/>"#)
+        );
+        let json: serde_json::Value = serde_json::from_str(&meta.to_abs_json().unwrap()).unwrap();
+        assert_eq!(json["duration"], 3723.5);
+    }
+}
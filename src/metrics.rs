@@ -0,0 +1,245 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Upper bounds (seconds) of the download duration histogram buckets.
+const DURATION_BUCKETS: [f64; 8] = [
+    30.0, 60.0, 300.0, 600.0, 1_800.0, 3_600.0, 7_200.0, 14_400.0,
+];
+
+struct Histogram {
+    counts: [u64; DURATION_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+/// Gauges of one account's last sync pass.
+#[derive(Default, Clone, Copy)]
+struct AccountGauges {
+    books_on_shelf: u64,
+    books_downloaded: u64,
+    books_pending: u64,
+    last_sync: u64,
+}
+
+#[derive(Default, Clone, Copy)]
+struct ApiCounters {
+    requests: u64,
+    errors: u64,
+}
+
+/// Process-wide counters and gauges exported on `/metrics` in the Prometheus
+/// text format.
+pub struct Metrics {
+    accounts: Mutex<BTreeMap<String, AccountGauges>>,
+    bytes_downloaded: AtomicU64,
+    active_downloads: AtomicU64,
+    login_failures: AtomicU64,
+    download_duration: Mutex<Histogram>,
+    api: Mutex<BTreeMap<&'static str, ApiCounters>>,
+}
+
+pub static METRICS: Metrics = Metrics {
+    accounts: Mutex::new(BTreeMap::new()),
+    bytes_downloaded: AtomicU64::new(0),
+    active_downloads: AtomicU64::new(0),
+    login_failures: AtomicU64::new(0),
+    download_duration: Mutex::new(Histogram {
+        counts: [0; DURATION_BUCKETS.len()],
+        sum: 0.0,
+        count: 0,
+    }),
+    api: Mutex::new(BTreeMap::new()),
+};
+
+/// Decrements the active download gauge when dropped.
+pub struct ActiveDownload(());
+
+impl Drop for ActiveDownload {
+    fn drop(&mut self) {
+        METRICS.active_downloads.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Metrics {
+    /// Book counts from a sync pass plan; filtered books count only towards
+    /// `on_shelf`. Nothing else sets them, so the gauges count one way only.
+    pub fn set_books(&self, account: &str, on_shelf: usize, downloaded: usize, pending: usize) {
+        let mut accounts = self.accounts.lock().unwrap();
+        let g = accounts.entry(account.to_owned()).or_default();
+        g.books_on_shelf = on_shelf as u64;
+        g.books_downloaded = downloaded as u64;
+        g.books_pending = pending as u64;
+    }
+
+    pub fn add_downloaded_bytes(&self, bytes: u64) {
+        self.bytes_downloaded.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn start_download(&self) -> ActiveDownload {
+        self.active_downloads.fetch_add(1, Ordering::Relaxed);
+        ActiveDownload(())
+    }
+
+    pub fn observe_download(&self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        let mut h = self.download_duration.lock().unwrap();
+        for (bound, count) in DURATION_BUCKETS.iter().zip(h.counts.iter_mut()) {
+            if secs <= *bound {
+                *count += 1;
+            }
+        }
+        h.sum += secs;
+        h.count += 1;
+    }
+
+    /// Counts one Storytel API call, passing `res` through unchanged.
+    pub fn observe_api<T>(&self, endpoint: &'static str, res: eyre::Result<T>) -> eyre::Result<T> {
+        let mut api = self.api.lock().unwrap();
+        let c = api.entry(endpoint).or_default();
+        c.requests += 1;
+        if res.is_err() {
+            c.errors += 1;
+        }
+        res
+    }
+
+    pub fn login_failed(&self) {
+        self.login_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn sync_succeeded(&self, account: &str) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        let mut accounts = self.accounts.lock().unwrap();
+        accounts.entry(account.to_owned()).or_default().last_sync = now;
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let mut scalar = |name: &str, help: &str, kind: &str, value: u64| {
+            writeln!(out, "# HELP {name} {help}").unwrap();
+            writeln!(out, "# TYPE {name} {kind}").unwrap();
+            writeln!(out, "{name} {value}").unwrap();
+        };
+
+        scalar(
+            "storytel_sync_downloaded_bytes_total",
+            "Audio bytes downloaded since start.",
+            "counter",
+            self.bytes_downloaded.load(Ordering::Relaxed),
+        );
+        scalar(
+            "storytel_sync_active_downloads",
+            "Downloads currently in progress.",
+            "gauge",
+            self.active_downloads.load(Ordering::Relaxed),
+        );
+        scalar(
+            "storytel_sync_login_failures_total",
+            "Failed Storytel logins.",
+            "counter",
+            self.login_failures.load(Ordering::Relaxed),
+        );
+        {
+            let h = self.download_duration.lock().unwrap();
+            let name = "storytel_sync_download_duration_seconds";
+            writeln!(out, "# HELP {name} Duration of completed audio downloads.").unwrap();
+            writeln!(out, "# TYPE {name} histogram").unwrap();
+            for (bound, count) in DURATION_BUCKETS.iter().zip(h.counts.iter()) {
+                writeln!(out, "{name}_bucket{{le=\"{bound}\"}} {count}").unwrap();
+            }
+            writeln!(out, "{name}_bucket{{le=\"+Inf\"}} {}", h.count).unwrap();
+            writeln!(out, "{name}_sum {}", h.sum).unwrap();
+            writeln!(out, "{name}_count {}", h.count).unwrap();
+        }
+
+        let accounts = self.accounts.lock().unwrap();
+        for (name, help, pick) in [
+            (
+                "storytel_sync_books_on_shelf",
+                "Audiobooks on the Storytel bookshelf, as of the last sync pass.",
+                (|g: &AccountGauges| g.books_on_shelf) as fn(&AccountGauges) -> u64,
+            ),
+            (
+                "storytel_sync_books_downloaded",
+                "Audiobooks selected for sync that are on disk, as of the last sync pass.",
+                |g: &AccountGauges| g.books_downloaded,
+            ),
+            (
+                "storytel_sync_books_pending",
+                "Audiobooks selected for sync not yet downloaded, as of the last sync pass.",
+                |g: &AccountGauges| g.books_pending,
+            ),
+            (
+                "storytel_sync_last_successful_sync_timestamp_seconds",
+                "Unix time of the last sync pass without failures.",
+                |g: &AccountGauges| g.last_sync,
+            ),
+        ] {
+            writeln!(out, "# HELP {name} {help}").unwrap();
+            writeln!(out, "# TYPE {name} gauge").unwrap();
+            for (account, g) in accounts.iter() {
+                let account = account.replace('\\', "\\\\").replace('"', "\\\"");
+                writeln!(out, "{name}{{account=\"{account}\"}} {}", pick(g)).unwrap();
+            }
+        }
+
+        let api = self.api.lock().unwrap();
+        for (name, help, pick) in [
+            (
+                "storytel_sync_api_requests_total",
+                "Storytel API requests by endpoint.",
+                (|c: &ApiCounters| c.requests) as fn(&ApiCounters) -> u64,
+            ),
+            (
+                "storytel_sync_api_errors_total",
+                "Failed Storytel API requests by endpoint.",
+                |c: &ApiCounters| c.errors,
+            ),
+        ] {
+            writeln!(out, "# HELP {name} {help}").unwrap();
+            writeln!(out, "# TYPE {name} counter").unwrap();
+            for (endpoint, c) in api.iter() {
+                writeln!(out, "{name}{{endpoint=\"{endpoint}\"}} {}", pick(c)).unwrap();
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn book_gauges_are_labelled_by_account() {
+        let m = Metrics {
+            accounts: Mutex::new(BTreeMap::new()),
+            bytes_downloaded: AtomicU64::new(0),
+            active_downloads: AtomicU64::new(0),
+            login_failures: AtomicU64::new(0),
+            download_duration: Mutex::new(Histogram {
+                counts: [0; DURATION_BUCKETS.len()],
+                sum: 0.0,
+                count: 0,
+            }),
+            api: Mutex::new(BTreeMap::new()),
+        };
+        m.set_books("anna", 10, 7, 3);
+        m.set_books("bob", 2, 2, 0);
+        m.set_books("anna", 11, 8, 3);
+        let out = m.render();
+        assert!(out.contains("storytel_sync_books_on_shelf{account=\"anna\"} 11\n"));
+        assert!(out.contains("storytel_sync_books_pending{account=\"anna\"} 3\n"));
+        assert!(out.contains("storytel_sync_books_downloaded{account=\"bob\"} 2\n"));
+        assert_eq!(
+            out.matches("# TYPE storytel_sync_books_on_shelf gauge")
+                .count(),
+            1
+        );
+    }
+}
@@ -0,0 +1,241 @@
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::time::Duration;
+
+/// Result of walking every MPEG audio frame header of a file.
+pub struct Mp3Scan {
+    pub frames: u64,
+    pub duration: Duration,
+    /// Bytes between frames that did not parse as a frame header.
+    pub junk_bytes: u64,
+    /// The frame after the last valid one extends past the end of the file.
+    pub truncated: bool,
+}
+
+const BITRATES_V1: [[u32; 15]; 3] = [
+    [
+        0, 32, 64, 96, 128, 160, 192, 224, 256, 288, 320, 352, 384, 416, 448,
+    ],
+    [
+        0, 32, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320, 384,
+    ],
+    [
+        0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320,
+    ],
+];
+const BITRATES_V2: [[u32; 15]; 2] = [
+    [
+        0, 32, 48, 56, 64, 80, 96, 112, 128, 144, 160, 176, 192, 224, 256,
+    ],
+    [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160],
+];
+
+struct Frame {
+    len: u64,
+    samples: u32,
+    sample_rate: u32,
+}
+
+fn parse_header(h: [u8; 4]) -> Option<Frame> {
+    if h[0] != 0xFF || h[1] & 0xE0 != 0xE0 {
+        return None;
+    }
+    let version = (h[1] >> 3) & 3; // 0 = 2.5, 2 = 2, 3 = 1
+    let layer = match (h[1] >> 1) & 3 {
+        3 => 1,
+        2 => 2,
+        1 => 3,
+        _ => return None,
+    };
+    let bitrate_idx = usize::from(h[2] >> 4);
+    let rate_idx = usize::from((h[2] >> 2) & 3);
+    if version == 1 || bitrate_idx == 0 || bitrate_idx == 15 || rate_idx == 3 {
+        return None;
+    }
+    let padding = u64::from((h[2] >> 1) & 1);
+
+    let mpeg1 = version == 3;
+    let kbps = if mpeg1 {
+        BITRATES_V1[layer - 1][bitrate_idx]
+    } else {
+        BITRATES_V2[usize::from(layer != 1)][bitrate_idx]
+    };
+    let sample_rate = [44_100, 48_000, 32_000][rate_idx]
+        / match version {
+            3 => 1,
+            2 => 2,
+            _ => 4,
+        };
+    let samples = match (layer, mpeg1) {
+        (1, _) => 384,
+        (3, false) => 576,
+        _ => 1152,
+    };
+    let slot = if layer == 1 { 4 } else { 1 };
+    let len = u64::from(samples / 8) * u64::from(kbps) * 1000 / u64::from(sample_rate) / slot
+        * slot
+        + padding * slot;
+    Some(Frame {
+        len,
+        samples,
+        sample_rate,
+    })
+}
+
+/// Walks the frame headers of an MP3 file, skipping ID3v2/ID3v1 tags.
+pub fn scan(path: &Path) -> std::io::Result<Mp3Scan> {
+    let mut file = File::open(path)?;
+    let mut end = file.metadata()?.len();
+
+    if end >= 128 {
+        let mut tag = [0u8; 3];
+        file.seek(SeekFrom::Start(end - 128))?;
+        file.read_exact(&mut tag)?;
+        if &tag == b"TAG" {
+            end -= 128;
+        }
+    }
+
+    file.seek(SeekFrom::Start(0))?;
+    let mut r = BufReader::with_capacity(1 << 16, file);
+    let mut pos = 0u64;
+    let mut id3 = [0u8; 10];
+    if end >= 10 {
+        r.read_exact(&mut id3)?;
+        if &id3[..3] == b"ID3" {
+            let size = id3[6..10]
+                .iter()
+                .fold(0u64, |acc, b| (acc << 7) | u64::from(b & 0x7F));
+            let footer = if id3[5] & 0x10 != 0 { 10 } else { 0 };
+            pos = 10 + size + footer;
+        }
+        r.seek(SeekFrom::Start(pos))?;
+    }
+
+    let mut scan = Mp3Scan {
+        frames: 0,
+        duration: Duration::ZERO,
+        junk_bytes: 0,
+        truncated: false,
+    };
+    let mut secs = 0f64;
+    let mut header = [0u8; 4];
+    // whether `pos` is right behind a valid frame; a header found in junk,
+    // e.g. an APE tag, may be a false sync and says nothing about truncation
+    let mut after_frame = false;
+    while pos + 4 <= end {
+        r.read_exact(&mut header)?;
+        match parse_header(header) {
+            Some(f) if pos + f.len <= end => {
+                scan.frames += 1;
+                secs += f64::from(f.samples) / f64::from(f.sample_rate);
+                r.seek_relative(f.len as i64 - 4)?;
+                pos += f.len;
+                after_frame = true;
+            }
+            Some(_) if after_frame => {
+                scan.truncated = true;
+                break;
+            }
+            _ => {
+                after_frame = false;
+                r.seek_relative(-3)?;
+                pos += 1;
+                scan.junk_bytes += 1;
+            }
+        }
+    }
+    scan.duration = Duration::from_secs_f64(secs);
+    Ok(scan)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// MPEG-1 Layer III, 128 kbit/s, 44.1 kHz, no padding: 417 bytes, 1152 samples.
+    const HEADER: [u8; 4] = [0xFF, 0xFB, 0x90, 0x00];
+
+    fn frame() -> Vec<u8> {
+        let mut f = HEADER.to_vec();
+        f.resize(417, 0);
+        f
+    }
+
+    fn scan_bytes(name: &str, data: &[u8]) -> Mp3Scan {
+        let path = std::env::temp_dir().join(format!(
+            "storytel-sync-mp3-{name}-{}.mp3",
+            std::process::id()
+        ));
+        std::fs::write(&path, data).unwrap();
+        let scan = scan(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        scan
+    }
+
+    #[test]
+    fn frame_lengths() {
+        let f = parse_header(HEADER).unwrap();
+        assert_eq!((f.len, f.samples, f.sample_rate), (417, 1152, 44_100));
+        // padding adds one byte
+        assert_eq!(parse_header([0xFF, 0xFB, 0x92, 0x00]).unwrap().len, 418);
+        // MPEG-2 Layer III, 64 kbit/s, 22.05 kHz
+        let f = parse_header([0xFF, 0xF3, 0x80, 0x00]).unwrap();
+        assert_eq!((f.len, f.samples, f.sample_rate), (208, 576, 22_050));
+    }
+
+    #[test]
+    fn invalid_headers() {
+        assert!(parse_header([0x00, 0xFB, 0x90, 0x00]).is_none());
+        // reserved version, free and bad bitrates, reserved sample rate
+        assert!(parse_header([0xFF, 0xEB, 0x90, 0x00]).is_none());
+        assert!(parse_header([0xFF, 0xFB, 0x00, 0x00]).is_none());
+        assert!(parse_header([0xFF, 0xFB, 0xF0, 0x00]).is_none());
+        assert!(parse_header([0xFF, 0xFB, 0x9C, 0x00]).is_none());
+    }
+
+    #[test]
+    fn skips_tags_and_counts_junk() {
+        let mut data = b"ID3\x04\x00\x00\x00\x00\x00\x14".to_vec();
+        data.resize(10 + 20, 0);
+        for _ in 0..100 {
+            data.extend(frame());
+        }
+        data.extend([1, 2, 3]);
+        data.extend(frame());
+        let mut tag = b"TAG".to_vec();
+        tag.resize(128, b' ');
+        data.extend(tag);
+
+        let scan = scan_bytes("tags", &data);
+        assert_eq!(scan.frames, 101);
+        assert_eq!(scan.junk_bytes, 3);
+        assert!(!scan.truncated);
+        let expected = 101.0 * 1152.0 / 44_100.0;
+        assert!((scan.duration.as_secs_f64() - expected).abs() < 1e-6);
+    }
+
+    #[test]
+    fn false_sync_in_trailing_junk_is_not_truncation() {
+        let mut data = frame();
+        data.extend(frame());
+        data.extend(b"APETAGEX");
+        data.extend(HEADER);
+        data.extend([0; 20]);
+        let scan = scan_bytes("false-sync", &data);
+        assert_eq!(scan.frames, 2);
+        assert!(!scan.truncated);
+        // the last three bytes are too short for a header
+        assert_eq!(scan.junk_bytes, 32 - 3);
+    }
+
+    #[test]
+    fn detects_truncation() {
+        let mut data = frame();
+        data.extend(&frame()[..200]);
+        let scan = scan_bytes("truncated", &data);
+        assert_eq!(scan.frames, 1);
+        assert!(scan.truncated);
+    }
+}
@@ -0,0 +1,204 @@
+use crate::config::{Prune, PrunePolicy};
+use crate::state::{Orphan, StateStore, book_key};
+use chrono::Utc;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+/// Book directories (`author/title` containing `audio.mp3`) under `dl_dir`.
+/// Hidden directories such as the state directory are ignored.
+pub fn scan_local(dl_dir: &Path) -> eyre::Result<Vec<PathBuf>> {
+    let visible = |e: &std::fs::DirEntry| {
+        !e.file_name().to_string_lossy().starts_with('.') && e.path().is_dir()
+    };
+    let mut books = Vec::new();
+    for author in std::fs::read_dir(dl_dir)? {
+        let author = author?;
+        if !visible(&author) {
+            continue;
+        }
+        for title in std::fs::read_dir(author.path())? {
+            let title = title?;
+            if visible(&title) && title.path().join("audio.mp3").exists() {
+                books.push(title.path());
+            }
+        }
+    }
+    Ok(books)
+}
+
+fn remove_if_empty(dir: &Path) {
+    if std::fs::read_dir(dir).is_ok_and(|mut d| d.next().is_none()) {
+        let _ = std::fs::remove_dir(dir);
+    }
+}
+
+fn copy_dir(src: &Path, dst: &Path) -> std::io::Result<()> {
+    std::fs::create_dir_all(dst)?;
+    for entry in std::fs::read_dir(src)? {
+        let entry = entry?;
+        let to = dst.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &to)?;
+        } else {
+            std::fs::copy(entry.path(), &to)?;
+        }
+    }
+    Ok(())
+}
+
+/// Moves a book directory, copying and then deleting it when `dst` is on
+/// another file system.
+fn move_dir(src: &Path, dst: &Path) -> eyre::Result<()> {
+    match std::fs::rename(src, dst) {
+        Err(e) if e.kind() == std::io::ErrorKind::CrossesDevices => {
+            if let Err(e) = copy_dir(src, dst) {
+                let _ = std::fs::remove_dir_all(dst);
+                return Err(e.into());
+            }
+            std::fs::remove_dir_all(src)?;
+            Ok(())
+        }
+        res => Ok(res?),
+    }
+}
+
+fn act(cfg: &Prune, dl_dir: &Path, key: &str) -> eyre::Result<()> {
+    let src = dl_dir.join(key);
+    match cfg.policy {
+        PrunePolicy::Archive => {
+            let archive = cfg
+                .archive_dir
+                .as_ref()
+                .ok_or_else(|| eyre::eyre!("prune.archive_dir is not set"))?;
+            let dst = archive.join(key);
+            if let Some(parent) = dst.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            move_dir(&src, &dst)?;
+            tracing::info!("prune: archived {key} to {}", dst.display());
+        }
+        PrunePolicy::Delete => {
+            std::fs::remove_dir_all(&src)?;
+            tracing::info!("prune: deleted {key}");
+        }
+        PrunePolicy::Keep | PrunePolicy::List => return Ok(()),
+    }
+    if let Some(author_dir) = src.parent() {
+        remove_if_empty(author_dir);
+    }
+    Ok(())
+}
+
+/// Whether removing `orphans` of `local` books exceeds `max_fraction`. One
+/// book may always go, so small libraries can be pruned too.
+fn too_many(orphans: usize, local: usize, max_fraction: f64) -> bool {
+    orphans > 1 && orphans as f64 > max_fraction * local as f64
+}
+
+/// Records local books missing from the shelf and, once their grace period
+/// is over, archives or deletes them according to `cfg.policy`.
+///
+/// `shelf_books` are the directories of every audiobook currently on the
+/// shelf. Nothing happens when the shelf is empty or when more than
+/// `max_fraction` of the local library (and more than one book) would be
+/// removed, since both usually mean the API returned a bogus list.
+pub fn run(
+    cfg: &Prune,
+    dl_dir: &Path,
+    shelf_books: &HashSet<PathBuf>,
+    store: &StateStore,
+) -> eyre::Result<()> {
+    if cfg.policy == PrunePolicy::Keep {
+        return Ok(());
+    }
+    if shelf_books.is_empty() {
+        tracing::warn!("prune: bookshelf is empty, skipping orphan detection");
+        return Ok(());
+    }
+
+    let local = scan_local(dl_dir)?;
+    let orphans: Vec<String> = local
+        .iter()
+        .filter(|b| !shelf_books.contains(*b))
+        .map(|b| book_key(dl_dir, b))
+        .collect();
+
+    let now = Utc::now().timestamp();
+    store.update(|s| {
+        s.orphans.retain(|k, _| orphans.contains(k));
+        for key in &orphans {
+            s.orphans
+                .entry(key.clone())
+                .or_insert(Orphan { first_seen: now });
+        }
+    })?;
+    if !orphans.is_empty() {
+        tracing::info!(
+            "prune: {} local books are no longer on the shelf",
+            orphans.len()
+        );
+    }
+
+    if cfg.policy == PrunePolicy::List || orphans.is_empty() {
+        return Ok(());
+    }
+    if too_many(orphans.len(), local.len(), cfg.max_fraction) {
+        tracing::warn!(
+            "prune: {} of {} local books would be removed, exceeding max_fraction={}; \
+             not touching anything",
+            orphans.len(),
+            local.len(),
+            cfg.max_fraction
+        );
+        return Ok(());
+    }
+
+    let grace = i64::from(cfg.grace_days) * 86_400;
+    let due: Vec<String> = store
+        .get()
+        .orphans
+        .into_iter()
+        .filter(|(_, o)| now - o.first_seen >= grace)
+        .map(|(k, _)| k)
+        .collect();
+    for key in due {
+        match act(cfg, dl_dir, &key) {
+            Ok(()) => {
+                store.update(|s| s.orphans.remove(&key))?;
+            }
+            Err(e) => tracing::error!("prune: cannot remove {key}: {e:#}"),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn one_book_may_always_go() {
+        assert!(!too_many(1, 1, 0.25));
+        assert!(!too_many(1, 3, 0.25));
+        assert!(too_many(2, 3, 0.25));
+        assert!(!too_many(2, 8, 0.25));
+        assert!(too_many(3, 8, 0.25));
+    }
+
+    #[test]
+    fn copy_dir_copies_nested_files() {
+        let root = std::env::temp_dir().join(format!("storytel-sync-prune-{}", std::process::id()));
+        let (src, dst) = (root.join("src"), root.join("dst/author/title"));
+        std::fs::create_dir_all(src.join("extra")).unwrap();
+        std::fs::write(src.join("audio.mp3"), b"mp3").unwrap();
+        std::fs::write(src.join("extra/notes.txt"), b"notes").unwrap();
+
+        copy_dir(&src, &dst).unwrap();
+        assert_eq!(std::fs::read(dst.join("audio.mp3")).unwrap(), b"mp3");
+        assert_eq!(
+            std::fs::read(dst.join("extra/notes.txt")).unwrap(),
+            b"notes"
+        );
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
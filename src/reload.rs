@@ -0,0 +1,243 @@
+use crate::config::Config;
+use crate::sync::SyncContext;
+use crate::throttle::Throttles;
+use actix_web::web;
+use chrono::Local;
+use serde::Serialize;
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Top-level keys only read at start-up.
+const RESTART_KEYS: [&str; 3] = ["download_dir", "state_dir", "dedupe_by_isbn"];
+/// Keys of an `[[accounts]]` entry only read at start-up.
+const ACCOUNT_RESTART_KEYS: [&str; 1] = ["download_subdir"];
+
+/// Outcome of the last config reload, shown on the bookshelf page and
+/// returned by `/api/config/reload`.
+#[derive(Serialize, Clone, Default)]
+pub struct ReloadReport {
+    pub reloaded_at: String,
+    /// Changed keys now in effect.
+    pub applied: Vec<String>,
+    /// Keys changed since start-up that keep their old value until a restart.
+    pub restart_required: Vec<String>,
+    /// Why the new config was rejected; nothing was applied then.
+    pub error: Option<String>,
+    /// Problems applying an accepted config, such as a failed login.
+    pub warnings: Vec<String>,
+}
+
+/// Re-reads the config file and applies what can change while running:
+/// sync settings, filters, schedule, bandwidth, retry, storage, prune,
+/// webhooks, media servers and credentials.
+pub struct Reloader {
+    path: PathBuf,
+    overrides: Vec<String>,
+    /// Config as loaded at start-up, compared for keys that need a restart.
+    started: Config,
+    /// Config as last applied; the lock also serializes reloads.
+    current: tokio::sync::Mutex<Config>,
+    accounts: Vec<SyncContext>,
+    throttles: web::Data<Throttles>,
+    last: std::sync::Mutex<Option<ReloadReport>>,
+}
+
+fn needs_restart(key: &str) -> bool {
+    match key.strip_prefix("accounts.") {
+        // an added or removed account
+        Some(rest) => match rest.split_once('.') {
+            Some((_, field)) => ACCOUNT_RESTART_KEYS.contains(&field),
+            None => true,
+        },
+        None => RESTART_KEYS.contains(&key),
+    }
+}
+
+fn same<T: Serialize>(a: &T, b: &T) -> bool {
+    serde_json::to_value(a).ok() == serde_json::to_value(b).ok()
+}
+
+/// Keys whose values differ; accounts are compared by name, e.g.
+/// `accounts.kids.filters`. Only key names are reported, never values.
+fn changed_keys(old: &Config, new: &Config) -> Vec<String> {
+    let (Ok(Value::Object(old)), Ok(Value::Object(new))) =
+        (serde_json::to_value(old), serde_json::to_value(new))
+    else {
+        return Vec::new();
+    };
+    let mut keys: Vec<String> = new
+        .iter()
+        .filter(|(k, v)| *k != "accounts" && old.get(*k) != Some(*v))
+        .map(|(k, _)| k.clone())
+        .collect();
+
+    let named = |doc: &serde_json::Map<String, Value>| -> Vec<(String, Value)> {
+        doc.get("accounts")
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+            .filter_map(|a| Some((a.get("name")?.as_str()?.to_owned(), a.clone())))
+            .collect()
+    };
+    let (old_accounts, new_accounts) = (named(&old), named(&new));
+    for (name, account) in &new_accounts {
+        match old_accounts.iter().find(|(n, _)| n == name) {
+            Some((_, before)) => {
+                let fields = account.as_object().into_iter().flatten();
+                keys.extend(
+                    fields
+                        .filter(|(k, v)| before.get(k.as_str()) != Some(*v))
+                        .map(|(k, _)| format!("accounts.{name}.{k}")),
+                );
+            }
+            None => keys.push(format!("accounts.{name}")),
+        }
+    }
+    keys.extend(
+        old_accounts
+            .iter()
+            .filter(|(n, _)| !new_accounts.iter().any(|(m, _)| m == n))
+            .map(|(n, _)| format!("accounts.{n}")),
+    );
+    keys.sort();
+    keys
+}
+
+impl Reloader {
+    pub fn new(
+        path: &Path,
+        overrides: Vec<String>,
+        started: Config,
+        accounts: Vec<SyncContext>,
+        throttles: web::Data<Throttles>,
+    ) -> Self {
+        Self {
+            path: path.to_owned(),
+            overrides,
+            current: tokio::sync::Mutex::new(started.clone()),
+            started,
+            accounts,
+            throttles,
+            last: std::sync::Mutex::new(None),
+        }
+    }
+
+    pub fn last(&self) -> Option<ReloadReport> {
+        self.last.lock().unwrap().clone()
+    }
+
+    /// Loads and validates the config file, then applies it. An invalid file
+    /// changes nothing.
+    pub async fn reload(&self) -> ReloadReport {
+        let mut current = self.current.lock().await;
+        let report = match self.apply(&mut current).await {
+            Ok(report) => {
+                tracing::info!(
+                    "reload: applied [{}], restart required for [{}]",
+                    report.applied.join(", "),
+                    report.restart_required.join(", ")
+                );
+                report
+            }
+            Err(e) => {
+                tracing::error!("reload: keeping the running config: {e:#}");
+                ReloadReport {
+                    reloaded_at: Local::now().to_rfc3339(),
+                    restart_required: self.last().unwrap_or_default().restart_required,
+                    error: Some(format!("{e:#}")),
+                    ..ReloadReport::default()
+                }
+            }
+        };
+        *self.last.lock().unwrap() = Some(report.clone());
+        report
+    }
+
+    async fn apply(&self, current: &mut Config) -> eyre::Result<ReloadReport> {
+        let new = Config::load(&self.path, &self.overrides)?;
+        let new_accounts = new.accounts()?;
+        self.throttles.update(&new.bandwidth)?;
+
+        let applied = changed_keys(current, &new)
+            .into_iter()
+            .filter(|k| !needs_restart(k))
+            .collect();
+        let restart_required = changed_keys(&self.started, &new)
+            .into_iter()
+            .filter(|k| needs_restart(k))
+            .collect();
+
+        let mut warnings = Vec::new();
+        for ctx in &self.accounts {
+            // accounts added since start-up wait for a restart
+            let Some((_, mut cfg)) = new_accounts
+                .iter()
+                .find(|(n, _)| *n == ctx.account)
+                .cloned()
+            else {
+                continue;
+            };
+            let old = ctx.cfg.get();
+            cfg.download_dir = old.download_dir.clone();
+            cfg.state_dir = old.state_dir.clone();
+            let reschedule =
+                cfg.sync_enabled != old.sync_enabled || !same(&cfg.schedule, &old.schedule);
+
+            ctx.shelf.set_ttl(cfg.cache.bookshelf_ttl_secs);
+            if let Err(e) = ctx.client.reconfigure(&cfg).await {
+                warnings.push(format!("login failed for account {}: {e:#}", ctx.account));
+            }
+            ctx.cfg.set(cfg);
+            if reschedule {
+                ctx.state.reschedule();
+            }
+        }
+        *current = new;
+        Ok(ReloadReport {
+            reloaded_at: Local::now().to_rfc3339(),
+            applied,
+            restart_required,
+            error: None,
+            warnings,
+        })
+    }
+}
+
+/// Reloads whenever the config file's modification time changes, checked
+/// every `reload.poll_secs` while `reload.watch` is on.
+pub async fn watch(reloader: web::Data<Reloader>) {
+    let modified = |p: &Path| std::fs::metadata(p).and_then(|m| m.modified()).ok();
+    let mut last = modified(&reloader.path);
+    loop {
+        let settings = reloader.current.lock().await.reload.clone();
+        tokio::time::sleep(Duration::from_secs(settings.poll_secs)).await;
+        if !settings.watch {
+            continue;
+        }
+        let now = modified(&reloader.path);
+        if now != last {
+            last = now;
+            tracing::info!("reload: {} changed", reloader.path.display());
+            reloader.reload().await;
+        }
+    }
+}
+
+/// Reloads on every SIGHUP.
+#[cfg(unix)]
+pub async fn on_sighup(reloader: web::Data<Reloader>) {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(s) => s,
+        Err(e) => {
+            tracing::error!("reload: cannot listen for SIGHUP: {e}");
+            return;
+        }
+    };
+    while hangup.recv().await.is_some() {
+        tracing::info!("reload: SIGHUP received");
+        reloader.reload().await;
+    }
+}
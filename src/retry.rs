@@ -0,0 +1,262 @@
+use crate::config::Retry;
+use chrono::{DateTime, Utc};
+use rand::{Rng, rng};
+use reqwest::{RequestBuilder, Response, StatusCode, header};
+use std::time::Duration;
+
+impl Retry {
+    pub fn validate(&self) -> eyre::Result<()> {
+        if self.max_attempts == 0 {
+            eyre::bail!("retry.max_attempts must be at least 1");
+        }
+        Ok(())
+    }
+
+    /// Delay before retry number `attempt` (1-based): exponential, capped at
+    /// `max_backoff_secs`, with up to 50% random jitter subtracted.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let max = Duration::from_secs(self.max_backoff_secs);
+        let base = Duration::from_millis(self.initial_backoff_ms)
+            .saturating_mul(1 << attempt.saturating_sub(1).min(20))
+            .min(max);
+        base.mul_f64(rng().random_range(0.5..=1.0))
+    }
+}
+
+fn retryable_status(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+/// Connection problems and timeouts; anything else will not go away by retrying.
+pub fn retryable_error(e: &reqwest::Error) -> bool {
+    e.is_connect() || e.is_timeout() || e.is_body()
+}
+
+/// Throttling or server error status that outlasted the retries.
+#[derive(Debug)]
+pub struct Unavailable(pub StatusCode);
+
+impl std::fmt::Display for Unavailable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "HTTP {}", self.0)
+    }
+}
+
+impl std::error::Error for Unavailable {}
+
+/// Whether `e` may go away by trying again later: network problems, the
+/// server being unavailable, or an interrupted or timed out I/O operation.
+/// Other local I/O errors, such as a full disk or a permission problem,
+/// need someone to act.
+pub fn is_transient(e: &eyre::Report) -> bool {
+    use std::io::ErrorKind;
+
+    e.chain().any(|c| {
+        c.is::<Unavailable>()
+            || c.downcast_ref::<std::io::Error>()
+                .is_some_and(|e| matches!(e.kind(), ErrorKind::Interrupted | ErrorKind::TimedOut))
+            || c.downcast_ref::<reqwest::Error>()
+                .is_some_and(|e| retryable_error(e) || e.status().is_some_and(retryable_status))
+    })
+}
+
+/// `Retry-After` value as either delay seconds or an HTTP date; a date in the
+/// past means no delay.
+fn parse_retry_after(value: &str, now: DateTime<Utc>) -> Option<Duration> {
+    if let Ok(secs) = value.trim().parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let at = DateTime::parse_from_rfc2822(value.trim()).ok()?;
+    Some((at.with_timezone(&Utc) - now).to_std().unwrap_or_default())
+}
+
+fn retry_after(resp: &Response) -> Option<Duration> {
+    let value = resp.headers().get(header::RETRY_AFTER)?.to_str().ok()?;
+    parse_retry_after(value, Utc::now())
+}
+
+/// Sends the request built by `make` until it succeeds, fails permanently or
+/// `policy.max_attempts` is reached. A `Retry-After` header replaces the
+/// computed backoff; when it asks for more than `policy.max_backoff_secs` the
+/// request fails right away instead of stalling the caller. Other error
+/// statuses are returned to the caller as-is.
+pub async fn send(
+    policy: &Retry,
+    what: &str,
+    make: impl Fn() -> RequestBuilder,
+) -> eyre::Result<Response> {
+    let mut attempt = 1;
+    loop {
+        let (wait, err): (_, eyre::Report) = match make().send().await {
+            Ok(resp) if retryable_status(resp.status()) => {
+                let status = resp.status();
+                match retry_after(&resp) {
+                    Some(wait) if wait > Duration::from_secs(policy.max_backoff_secs) => {
+                        return Err(eyre::Report::new(Unavailable(status)).wrap_err(format!(
+                            "{what}: server asks to retry after {}s",
+                            wait.as_secs()
+                        )));
+                    }
+                    Some(wait) => (wait, Unavailable(status).into()),
+                    None => (policy.backoff(attempt), Unavailable(status).into()),
+                }
+            }
+            Ok(resp) => return Ok(resp),
+            Err(e) if retryable_error(&e) => (policy.backoff(attempt), e.into()),
+            Err(e) => return Err(e.into()),
+        };
+        if attempt >= policy.max_attempts {
+            return Err(err.wrap_err(format!("{what}: giving up after {attempt} attempts")));
+        }
+        tracing::warn!(
+            "{what}: attempt {attempt} failed ({err:#}), retrying in {:.1}s",
+            wait.as_secs_f64()
+        );
+        tokio::time::sleep(wait).await;
+        attempt += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    fn policy(max_attempts: u32) -> Retry {
+        Retry {
+            max_attempts,
+            initial_backoff_ms: 10,
+            max_backoff_secs: 60,
+        }
+    }
+
+    #[test]
+    fn retry_after_values() {
+        let now = DateTime::parse_from_rfc2822("Wed, 21 Oct 2026 07:28:00 GMT")
+            .unwrap()
+            .with_timezone(&Utc);
+        assert_eq!(
+            parse_retry_after(" 120 ", now),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2026 07:29:30 GMT", now),
+            Some(Duration::from_secs(90))
+        );
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2026 07:00:00 GMT", now),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(parse_retry_after("soon", now), None);
+        assert_eq!(parse_retry_after("-5", now), None);
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        let policy = Retry {
+            max_attempts: 10,
+            initial_backoff_ms: 1_000,
+            max_backoff_secs: 5,
+        };
+        for (attempt, full) in [(1, 1_000), (2, 2_000), (3, 4_000), (4, 5_000), (30, 5_000)] {
+            let wait = policy.backoff(attempt).as_millis();
+            assert!(
+                (full / 2..=full).contains(&wait),
+                "attempt {attempt}: {wait}ms"
+            );
+        }
+    }
+
+    /// Answers each connection with the next canned response; returns how
+    /// many requests were served.
+    async fn stub_server(responses: Vec<&'static str>) -> (String, tokio::task::JoinHandle<usize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let handle = tokio::spawn(async move {
+            let mut served = 0;
+            for resp in responses {
+                let Ok(Ok((mut sock, _))) =
+                    tokio::time::timeout(Duration::from_secs(1), listener.accept()).await
+                else {
+                    break;
+                };
+                let mut buf = [0; 4096];
+                let _ = sock.read(&mut buf).await.unwrap();
+                sock.write_all(resp.as_bytes()).await.unwrap();
+                served += 1;
+            }
+            served
+        });
+        (url, handle)
+    }
+
+    const BUSY: &str =
+        "HTTP/1.1 503 Busy\r\nretry-after: 0\r\nconnection: close\r\ncontent-length: 0\r\n\r\n";
+    const OK: &str = "HTTP/1.1 200 OK\r\nconnection: close\r\ncontent-length: 0\r\n\r\n";
+
+    #[tokio::test]
+    async fn retries_until_success() {
+        let (url, server) = stub_server(vec![BUSY, BUSY, OK]).await;
+        let http = reqwest::Client::new();
+        let resp = send(&policy(5), "test", || http.get(&url)).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(server.await.unwrap(), 3);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_max_attempts() {
+        let (url, server) = stub_server(vec![BUSY, BUSY, BUSY]).await;
+        let http = reqwest::Client::new();
+        let err = send(&policy(2), "test", || http.get(&url))
+            .await
+            .unwrap_err();
+        assert!(format!("{err:#}").contains("giving up after 2 attempts"));
+        assert!(is_transient(&err));
+        assert_eq!(server.await.unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn client_errors_are_permanent() {
+        let (url, server) = stub_server(vec![
+            "HTTP/1.1 404 Not Found\r\nconnection: close\r\ncontent-length: 0\r\n\r\n",
+        ])
+        .await;
+        let http = reqwest::Client::new();
+        let resp = send(&policy(5), "test", || http.get(&url)).await.unwrap();
+        let err = eyre::Report::new(resp.error_for_status().unwrap_err());
+        assert!(!is_transient(&err));
+        assert!(!is_transient(&eyre::eyre!("audio is corrupt")));
+        assert_eq!(server.await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn long_retry_after_fails_fast() {
+        let (url, server) = stub_server(vec![
+            "HTTP/1.1 429 Slow down\r\nretry-after: 3600\r\nconnection: close\r\ncontent-length: 0\r\n\r\n",
+            OK,
+        ])
+        .await;
+        let http = reqwest::Client::new();
+        let started = std::time::Instant::now();
+        let err = send(&policy(5), "test", || http.get(&url))
+            .await
+            .unwrap_err();
+        assert!(format!("{err:#}").contains("retry after 3600s"));
+        assert!(is_transient(&err));
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(server.await.unwrap(), 1);
+    }
+
+    #[test]
+    fn only_interrupted_io_is_transient() {
+        use std::io::{Error, ErrorKind};
+
+        let io = |kind| eyre::Report::new(Error::from(kind)).wrap_err("download");
+        assert!(is_transient(&io(ErrorKind::TimedOut)));
+        assert!(is_transient(&io(ErrorKind::Interrupted)));
+        assert!(!is_transient(&io(ErrorKind::StorageFull)));
+        assert!(!is_transient(&io(ErrorKind::PermissionDenied)));
+        assert!(!is_transient(&io(ErrorKind::NotFound)));
+    }
+}
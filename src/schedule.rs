@@ -0,0 +1,220 @@
+use crate::config::Schedule;
+use chrono::{DateTime, Duration as ChronoDuration, Local, NaiveTime};
+use croner::Cron;
+use eyre::WrapErr;
+use rand::{Rng, rng};
+use std::time::Duration;
+
+/// Daily time-of-day range such as `23:00-07:00`; may wrap past midnight.
+#[derive(Clone, Copy)]
+pub struct TimeWindow {
+    start: NaiveTime,
+    end: NaiveTime,
+}
+
+impl TimeWindow {
+    pub fn parse(s: &str) -> eyre::Result<Self> {
+        let (start, end) = s
+            .split_once('-')
+            .ok_or_else(|| eyre::eyre!("expected HH:MM-HH:MM, got {s:?}"))?;
+        Ok(Self {
+            start: NaiveTime::parse_from_str(start.trim(), "%H:%M")?,
+            end: NaiveTime::parse_from_str(end.trim(), "%H:%M")?,
+        })
+    }
+
+    pub fn contains(&self, t: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= t && t < self.end
+        } else {
+            t >= self.start || t < self.end
+        }
+    }
+
+    /// Time left until the window closes, or `None` if `now` is outside it.
+    pub fn remaining(&self, now: DateTime<Local>) -> Option<Duration> {
+        let t = now.time();
+        if !self.contains(t) {
+            return None;
+        }
+        let mut left = self.end - t;
+        if left <= ChronoDuration::zero() {
+            left += ChronoDuration::days(1);
+        }
+        left.to_std().ok()
+    }
+}
+
+fn parse_cron(expr: &str) -> eyre::Result<Cron> {
+    Cron::new(expr)
+        .parse()
+        .map_err(|e| eyre::eyre!("invalid cron expression {expr:?}: {e}"))
+}
+
+/// A scheduled pass: the regular slot it belongs to and when it starts,
+/// which jitter may move to either side of the slot.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Run {
+    pub slot: DateTime<Local>,
+    pub at: DateTime<Local>,
+}
+
+impl Schedule {
+    pub fn validate(&self) -> eyre::Result<()> {
+        if let Some(expr) = &self.cron {
+            parse_cron(expr).wrap_err("schedule.cron")?;
+        } else if self.interval_secs == 0 {
+            eyre::bail!("schedule.interval_secs must be greater than zero");
+        } else if self.jitter() >= self.interval_secs {
+            eyre::bail!("schedule.jitter_secs must be smaller than schedule.interval_secs");
+        }
+        if let Some(q) = &self.quiet_hours {
+            TimeWindow::parse(q).wrap_err("schedule.quiet_hours")?;
+        }
+        Ok(())
+    }
+
+    fn jitter(&self) -> u64 {
+        self.jitter_secs
+            .unwrap_or(if self.cron.is_some() { 0 } else { 7_200 })
+    }
+
+    /// When the first pass after start-up should run.
+    pub fn first_run(&self, now: DateTime<Local>) -> eyre::Result<Run> {
+        if self.run_on_start {
+            Ok(Run { slot: now, at: now })
+        } else if self.cron.is_some() {
+            self.next_run(now, now)
+        } else {
+            let at = now + ChronoDuration::seconds(self.initial_delay_secs as i64);
+            Ok(Run { slot: at, at })
+        }
+    }
+
+    /// The first slot after `after` and its jittered start, not before `now`.
+    /// Pass the later of the previous slot and its actual start as `after`:
+    /// a pass that jitter started early must not land in its own slot again.
+    pub fn next_run(&self, after: DateTime<Local>, now: DateTime<Local>) -> eyre::Result<Run> {
+        let slot = match &self.cron {
+            Some(expr) => parse_cron(expr)?
+                .find_next_occurrence(&after, false)
+                .map_err(|e| eyre::eyre!("cron expression {expr:?}: {e}"))?,
+            None => after + ChronoDuration::seconds(self.interval_secs as i64),
+        };
+        let jitter = self.jitter() as i64;
+        let offset = if jitter > 0 {
+            rng().random_range(-jitter..=jitter)
+        } else {
+            0
+        };
+        Ok(Run {
+            slot,
+            at: (slot + ChronoDuration::seconds(offset)).max(now),
+        })
+    }
+
+    /// How long to hold off before starting a download because of quiet hours.
+    pub fn quiet_wait(&self, now: DateTime<Local>) -> Option<Duration> {
+        self.quiet_hours
+            .as_deref()
+            .and_then(|q| TimeWindow::parse(q).ok())
+            .and_then(|w| w.remaining(now))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn at(h: u32, m: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(h, m, 0).unwrap()
+    }
+
+    #[test]
+    fn window_within_a_day() {
+        let w = TimeWindow::parse("01:00 - 07:30").unwrap();
+        assert!(!w.contains(at(0, 59)));
+        assert!(w.contains(at(1, 0)));
+        assert!(w.contains(at(7, 29)));
+        assert!(!w.contains(at(7, 30)));
+    }
+
+    #[test]
+    fn window_past_midnight() {
+        let w = TimeWindow::parse("23:00-07:00").unwrap();
+        assert!(w.contains(at(23, 0)));
+        assert!(w.contains(at(0, 0)));
+        assert!(w.contains(at(6, 59)));
+        assert!(!w.contains(at(7, 0)));
+        assert!(!w.contains(at(12, 0)));
+        assert!(!w.contains(at(22, 59)));
+    }
+
+    #[test]
+    fn window_remaining() {
+        let w = TimeWindow::parse("23:00-07:00").unwrap();
+        let now = |h, m| Local.with_ymd_and_hms(2026, 1, 15, h, m, 0).unwrap();
+        assert_eq!(
+            w.remaining(now(23, 30)),
+            Some(Duration::from_secs(7 * 3600 + 1800))
+        );
+        assert_eq!(w.remaining(now(6, 0)), Some(Duration::from_secs(3600)));
+        assert_eq!(w.remaining(now(12, 0)), None);
+    }
+
+    #[test]
+    fn window_rejects_garbage() {
+        assert!(TimeWindow::parse("23:00").is_err());
+        assert!(TimeWindow::parse("25:00-07:00").is_err());
+        assert!(TimeWindow::parse("night").is_err());
+    }
+
+    #[test]
+    fn cron_schedule_without_jitter() {
+        let schedule = Schedule {
+            cron: Some("30 3 * * *".into()),
+            ..Schedule::default()
+        };
+        let now = Local.with_ymd_and_hms(2026, 1, 15, 12, 0, 0).unwrap();
+        let slot = Local.with_ymd_and_hms(2026, 1, 16, 3, 30, 0).unwrap();
+        assert_eq!(schedule.next_run(now, now).unwrap(), Run { slot, at: slot });
+    }
+
+    #[test]
+    fn early_start_does_not_repeat_the_slot() {
+        let schedule = Schedule {
+            cron: Some("30 3 * * *".into()),
+            jitter_secs: Some(7_200),
+            ..Schedule::default()
+        };
+        let slot = Local.with_ymd_and_hms(2026, 1, 16, 3, 30, 0).unwrap();
+        // jitter started the pass two hours early
+        let started = slot - ChronoDuration::hours(2);
+        for _ in 0..20 {
+            let next = schedule.next_run(started.max(slot), started).unwrap();
+            assert_eq!(next.slot, slot + ChronoDuration::days(1));
+            assert!(next.at >= next.slot - ChronoDuration::hours(2));
+        }
+    }
+
+    #[test]
+    fn jitter_defaults_and_limits() {
+        let cron = Schedule {
+            cron: Some("0 * * * *".into()),
+            ..Schedule::default()
+        };
+        assert_eq!(cron.jitter(), 0);
+        assert_eq!(Schedule::default().jitter(), 7_200);
+        let hourly = Schedule {
+            interval_secs: 3_600,
+            ..Schedule::default()
+        };
+        assert!(hourly.validate().is_err());
+        let hourly = Schedule {
+            jitter_secs: Some(600),
+            ..hourly
+        };
+        assert!(hourly.validate().is_ok());
+    }
+}
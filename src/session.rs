@@ -0,0 +1,53 @@
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::path::Path;
+
+/// Storytel login kept in `session.json` so restarts do not log in again.
+#[derive(Serialize, Deserialize)]
+pub struct SavedSession {
+    /// Account the token belongs to; a different configured email ignores it.
+    pub email: String,
+    pub token: String,
+    /// Unix time of the login.
+    pub saved_at: i64,
+}
+
+/// Reads the saved session; a missing or unreadable file means none.
+pub fn load(path: &Path) -> Option<SavedSession> {
+    let content = match std::fs::read_to_string(path) {
+        Ok(c) => c,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return None,
+        Err(e) => {
+            tracing::warn!("session: cannot read {}: {e}", path.display());
+            return None;
+        }
+    };
+    serde_json::from_str(&content)
+        .inspect_err(|e| tracing::warn!("session: ignoring {}: {e}", path.display()))
+        .ok()
+}
+
+/// Writes the session atomically, readable by the owner only.
+pub fn save(path: &Path, session: &SavedSession) -> eyre::Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let tmp = path.with_extension("json.tmp");
+    let mut opts = std::fs::OpenOptions::new();
+    opts.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut opts, 0o600);
+    let mut file = opts.open(&tmp)?;
+    file.write_all(&serde_json::to_vec_pretty(session)?)?;
+    file.sync_all()?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+pub fn remove(path: &Path) {
+    if let Err(e) = std::fs::remove_file(path)
+        && e.kind() != std::io::ErrorKind::NotFound
+    {
+        tracing::warn!("session: cannot remove {}: {e}", path.display());
+    }
+}
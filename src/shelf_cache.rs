@@ -0,0 +1,192 @@
+use crate::client_storytel_api::{BookShelf, ShelfFetch, StorytelClient, Validators};
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{Arc, RwLock};
+
+/// Last bookshelf response as kept in `bookshelf.json`.
+#[derive(Serialize, Deserialize)]
+struct Stored {
+    /// Unix time the response was fetched or last confirmed unchanged.
+    fetched_at: i64,
+    #[serde(default)]
+    validators: Validators,
+    /// Response body as received from Storytel.
+    body: String,
+}
+
+#[derive(Clone)]
+struct Entry {
+    shelf: Arc<BookShelf>,
+    body: Arc<String>,
+    validators: Validators,
+    fetched_at: i64,
+}
+
+/// Bookshelf handed out by [`ShelfCache`].
+pub struct CachedShelf {
+    pub shelf: Arc<BookShelf>,
+    /// Unix time the shelf was fetched from Storytel.
+    pub fetched_at: i64,
+    /// Storytel could not be reached and the shelf is older than the TTL.
+    pub stale: bool,
+}
+
+/// Bookshelf of one account, kept in memory and in `bookshelf.json` in the
+/// state directory so pages render without asking Storytel every time.
+pub struct ShelfCache {
+    path: PathBuf,
+    ttl_secs: AtomicI64,
+    entry: RwLock<Option<Entry>>,
+    /// Serializes refreshes so concurrent requests fetch the shelf once.
+    refreshing: tokio::sync::Mutex<()>,
+}
+
+impl ShelfCache {
+    /// Loads the shelf saved by an earlier run, if any.
+    pub fn open(state_dir: &Path, ttl_secs: u64) -> Self {
+        let path = state_dir.join("bookshelf.json");
+        let entry = load(&path);
+        Self {
+            path,
+            ttl_secs: AtomicI64::new(ttl_secs.try_into().unwrap_or(i64::MAX)),
+            entry: RwLock::new(entry),
+            refreshing: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    pub fn set_ttl(&self, ttl_secs: u64) {
+        self.ttl_secs
+            .store(ttl_secs.try_into().unwrap_or(i64::MAX), Ordering::Relaxed);
+    }
+
+    fn cached(&self, stale: bool) -> Option<CachedShelf> {
+        self.entry.read().unwrap().as_ref().map(|e| CachedShelf {
+            shelf: e.shelf.clone(),
+            fetched_at: e.fetched_at,
+            stale,
+        })
+    }
+
+    fn fetched_at(&self) -> Option<i64> {
+        self.entry.read().unwrap().as_ref().map(|e| e.fetched_at)
+    }
+
+    /// Cached shelf without asking Storytel, e.g. while logged out.
+    pub fn peek(&self) -> Option<CachedShelf> {
+        self.cached(true)
+    }
+
+    /// Cached shelf while it is younger than the TTL, otherwise a fresh one.
+    /// When Storytel cannot be reached the cached shelf is returned as stale.
+    pub async fn get(&self, client: &StorytelClient) -> eyre::Result<CachedShelf> {
+        let now = chrono::Utc::now().timestamp();
+        if let Some(at) = self.fetched_at()
+            && now.saturating_sub(at) < self.ttl_secs.load(Ordering::Relaxed)
+        {
+            return Ok(self.cached(false).unwrap());
+        }
+        match self.refresh(client).await {
+            Ok(shelf) => Ok(shelf),
+            Err(e) => match self.cached(true) {
+                Some(shelf) => {
+                    tracing::warn!("bookshelf: serving cached shelf, refresh failed: {e:#}");
+                    Ok(shelf)
+                }
+                None => Err(e),
+            },
+        }
+    }
+
+    /// Asks Storytel for the shelf regardless of the TTL, sending the
+    /// validators of the cached response so an unchanged shelf is not
+    /// transferred again.
+    pub async fn refresh(&self, client: &StorytelClient) -> eyre::Result<CachedShelf> {
+        let before = self.fetched_at();
+        let _guard = self.refreshing.lock().await;
+        // another request refreshed while this one waited
+        if self.fetched_at() != before {
+            return Ok(self.cached(false).unwrap());
+        }
+
+        let validators = self
+            .entry
+            .read()
+            .unwrap()
+            .as_ref()
+            .map(|e| e.validators.clone())
+            .unwrap_or_default();
+        let now = chrono::Utc::now().timestamp();
+        let entry = match client.get_bookshelf_if_changed(&validators).await? {
+            ShelfFetch::NotModified => {
+                tracing::debug!("bookshelf: not modified");
+                let mut entry = self
+                    .entry
+                    .read()
+                    .unwrap()
+                    .clone()
+                    .ok_or_else(|| eyre::eyre!("304 for a bookshelf that is not cached"))?;
+                entry.fetched_at = now;
+                entry
+            }
+            ShelfFetch::Fetched {
+                shelf,
+                body,
+                validators,
+            } => Entry {
+                shelf: Arc::new(shelf),
+                body: Arc::new(body),
+                validators,
+                fetched_at: now,
+            },
+        };
+        if let Err(e) = save(&self.path, &entry) {
+            tracing::warn!("bookshelf: cannot save {}: {e:#}", self.path.display());
+        }
+        *self.entry.write().unwrap() = Some(entry);
+        Ok(self.cached(false).unwrap())
+    }
+}
+
+/// Reads the saved shelf; a missing or unreadable file means none.
+fn load(path: &Path) -> Option<Entry> {
+    let content = match std::fs::read_to_string(path) {
+        Ok(c) => c,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return None,
+        Err(e) => {
+            tracing::warn!("bookshelf: cannot read {}: {e}", path.display());
+            return None;
+        }
+    };
+    let parsed = serde_json::from_str::<Stored>(&content).and_then(|s| {
+        let shelf = serde_json::from_str::<BookShelf>(&s.body)?;
+        Ok(Entry {
+            shelf: Arc::new(shelf),
+            body: Arc::new(s.body),
+            validators: s.validators,
+            fetched_at: s.fetched_at,
+        })
+    });
+    parsed
+        .inspect_err(|e| tracing::warn!("bookshelf: ignoring {}: {e}", path.display()))
+        .ok()
+}
+
+/// Writes the shelf atomically.
+fn save(path: &Path, entry: &Entry) -> eyre::Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let stored = Stored {
+        fetched_at: entry.fetched_at,
+        validators: entry.validators.clone(),
+        body: entry.body.as_str().to_owned(),
+    };
+    let tmp = path.with_extension("json.tmp");
+    let mut file = std::fs::File::create(&tmp)?;
+    file.write_all(&serde_json::to_vec(&stored)?)?;
+    file.sync_all()?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
@@ -0,0 +1,173 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio::time::Instant;
+
+/// Part of the shutdown budget kept for checkpointing downloads that did not
+/// finish; at most half of the budget.
+const CHECKPOINT_RESERVE: Duration = Duration::from_secs(2);
+
+/// Download stopped by a shutdown; its `audio.mp3.part` is kept for resuming.
+#[derive(Debug)]
+pub struct Interrupted;
+
+impl std::fmt::Display for Interrupted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("interrupted by shutdown")
+    }
+}
+
+impl std::error::Error for Interrupted {}
+
+pub fn is_interrupted<T>(res: &eyre::Result<T>) -> bool {
+    res.as_ref()
+        .is_err_and(|e| e.downcast_ref::<Interrupted>().is_some())
+}
+
+/// Coordinates a clean exit: no new downloads start, running ones get a
+/// grace period and are then told to checkpoint.
+pub struct Shutdown {
+    stopping: AtomicBool,
+    checkpoint: AtomicBool,
+    active: AtomicUsize,
+    changed: Notify,
+}
+
+pub static SHUTDOWN: Shutdown = Shutdown {
+    stopping: AtomicBool::new(false),
+    checkpoint: AtomicBool::new(false),
+    active: AtomicUsize::new(0),
+    changed: Notify::const_new(),
+};
+
+/// A running download; dropping it lets [`Shutdown::drain`] go on.
+pub struct ActiveDownload(());
+
+impl Drop for ActiveDownload {
+    fn drop(&mut self) {
+        SHUTDOWN.active.fetch_sub(1, Ordering::SeqCst);
+        SHUTDOWN.changed.notify_waiters();
+    }
+}
+
+impl Shutdown {
+    pub fn is_stopping(&self) -> bool {
+        self.stopping.load(Ordering::SeqCst)
+    }
+
+    /// Registers a download; `None` once shutdown has begun.
+    pub fn track(&self) -> Option<ActiveDownload> {
+        self.active.fetch_add(1, Ordering::SeqCst);
+        let guard = ActiveDownload(());
+        (!self.is_stopping()).then_some(guard)
+    }
+
+    async fn wait_until(&self, done: impl Fn() -> bool) {
+        loop {
+            let changed = self.changed.notified();
+            tokio::pin!(changed);
+            changed.as_mut().enable();
+            if done() {
+                return;
+            }
+            changed.await;
+        }
+    }
+
+    /// Resolves when running downloads should save their progress and stop.
+    pub async fn checkpoint_requested(&self) {
+        self.wait_until(|| self.checkpoint.load(Ordering::SeqCst))
+            .await;
+    }
+
+    /// Stops new downloads and lets running ones finish; those still running
+    /// shortly before `deadline` are checkpointed. Returns by `deadline`.
+    pub async fn drain(&self, deadline: Instant) {
+        self.stopping.store(true, Ordering::SeqCst);
+        self.changed.notify_waiters();
+        let idle = || self.active.load(Ordering::SeqCst) == 0;
+        if idle() {
+            return;
+        }
+        let budget = deadline.saturating_duration_since(Instant::now());
+        let finish_by = deadline - CHECKPOINT_RESERVE.min(budget / 2);
+        tracing::info!(
+            "shutdown: waiting up to {:.1}s for {} running downloads",
+            finish_by
+                .saturating_duration_since(Instant::now())
+                .as_secs_f64(),
+            self.active.load(Ordering::SeqCst)
+        );
+        if tokio::time::timeout_at(finish_by, self.wait_until(idle))
+            .await
+            .is_ok()
+        {
+            return;
+        }
+        tracing::info!(
+            "shutdown: checkpointing {} running downloads",
+            self.active.load(Ordering::SeqCst)
+        );
+        self.checkpoint.store(true, Ordering::SeqCst);
+        self.changed.notify_waiters();
+        if tokio::time::timeout_at(deadline, self.wait_until(idle))
+            .await
+            .is_err()
+        {
+            tracing::warn!("shutdown: downloads did not stop in time");
+        }
+    }
+}
+
+/// Resolves on SIGTERM or SIGINT.
+pub async fn signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut term) => {
+                tokio::select! {
+                    _ = term.recv() => {}
+                    _ = tokio::signal::ctrl_c() => {}
+                }
+                return;
+            }
+            Err(e) => tracing::error!("shutdown: cannot listen for SIGTERM: {e}"),
+        }
+    }
+    if let Err(e) = tokio::signal::ctrl_c().await {
+        tracing::error!("shutdown: cannot listen for SIGINT: {e}");
+        std::future::pending::<()>().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn drain_checkpoints_before_the_deadline() {
+        let shutdown = Shutdown {
+            stopping: AtomicBool::new(false),
+            checkpoint: AtomicBool::new(false),
+            active: AtomicUsize::new(1),
+            changed: Notify::const_new(),
+        };
+        let started = Instant::now();
+        let deadline = started + Duration::from_secs(1);
+        // a download that only stops when asked to checkpoint
+        let download = async {
+            shutdown.checkpoint_requested().await;
+            let at = started.elapsed();
+            shutdown.active.fetch_sub(1, Ordering::SeqCst);
+            shutdown.changed.notify_waiters();
+            at
+        };
+        let (checkpointed_at, ()) = tokio::join!(download, shutdown.drain(deadline));
+        assert!(shutdown.is_stopping());
+        // half of a budget shorter than twice the reserve
+        assert!(checkpointed_at >= Duration::from_millis(500));
+        assert!(Instant::now() < deadline);
+    }
+}
@@ -0,0 +1,157 @@
+use fs4::fs_std::FileExt;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Local book that is no longer on the Storytel shelf.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Orphan {
+    /// Unix time the book was first found missing from the shelf.
+    pub first_seen: i64,
+}
+
+/// Downloaded book whose audio failed verification.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct CorruptBook {
+    pub reason: String,
+    /// Unix time of the last failed verification.
+    pub detected_at: i64,
+    /// Failed verifications in a row; re-downloads stop at [`MAX_REDOWNLOADS`].
+    pub attempts: u32,
+}
+
+pub const MAX_REDOWNLOADS: u32 = 3;
+
+/// Download started from the web UI that has not finished yet; restarted
+/// after a restart.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct QueuedDownload {
+    /// Unix time of the request.
+    pub requested_at: i64,
+}
+
+/// Library bookkeeping persisted between runs.
+/// Maps are keyed by the book directory relative to `download_dir` (`author/title`).
+#[derive(Serialize, Deserialize, Default, Clone)]
+#[serde(default)]
+pub struct LibraryState {
+    pub orphans: BTreeMap<String, Orphan>,
+    pub corrupt: BTreeMap<String, CorruptBook>,
+    /// Keyed by abook id.
+    pub queue: BTreeMap<u64, QueuedDownload>,
+}
+
+impl LibraryState {
+    pub fn mark_corrupt(&mut self, key: &str, reason: &str) {
+        let entry = self
+            .corrupt
+            .entry(key.to_owned())
+            .or_insert_with(|| CorruptBook {
+                reason: String::new(),
+                detected_at: 0,
+                attempts: 0,
+            });
+        entry.reason = reason.to_owned();
+        entry.detected_at = chrono::Utc::now().timestamp();
+        entry.attempts += 1;
+    }
+
+    /// Flagged corrupt and not yet given up on.
+    pub fn needs_redownload(&self, key: &str) -> Option<&CorruptBook> {
+        self.corrupt
+            .get(key)
+            .filter(|c| c.attempts <= MAX_REDOWNLOADS)
+    }
+}
+
+/// Key of `book_dir` in the [`LibraryState`] maps.
+pub fn book_key(dl_dir: &Path, book_dir: &Path) -> String {
+    book_dir
+        .strip_prefix(dl_dir)
+        .unwrap_or(book_dir)
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Reads `state.json` without creating anything; a missing file is an empty
+/// state.
+pub fn load(state_dir: &Path) -> eyre::Result<LibraryState> {
+    match std::fs::read_to_string(state_dir.join("state.json")) {
+        Ok(s) => Ok(serde_json::from_str(&s)?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(LibraryState::default()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// [`LibraryState`] backed by `state.json` in the state directory. The
+/// state is kept in memory, so the store locks the directory: a second
+/// process would have its writes overwritten.
+pub struct StateStore {
+    path: PathBuf,
+    data: Mutex<LibraryState>,
+    /// `state.lock`, locked until the store is dropped.
+    _lock: std::fs::File,
+}
+
+impl StateStore {
+    pub fn open(state_dir: &Path) -> eyre::Result<Self> {
+        std::fs::create_dir_all(state_dir)?;
+        let lock = std::fs::File::create(state_dir.join("state.lock"))?;
+        eyre::ensure!(
+            lock.try_lock_exclusive()?,
+            "{} is in use by another storytel-sync process; stop the server first",
+            state_dir.display()
+        );
+        Ok(Self {
+            path: state_dir.join("state.json"),
+            data: Mutex::new(load(state_dir)?),
+            _lock: lock,
+        })
+    }
+
+    pub fn get(&self) -> LibraryState {
+        self.data.lock().unwrap().clone()
+    }
+
+    /// Applies `f` and writes the result to disk atomically.
+    pub fn update<R>(&self, f: impl FnOnce(&mut LibraryState) -> R) -> eyre::Result<R> {
+        let mut data = self.data.lock().unwrap();
+        let r = f(&mut data);
+        self.write(&data, false)?;
+        Ok(r)
+    }
+
+    /// Writes the state and waits until it is on disk; called at shutdown.
+    pub fn flush(&self) -> eyre::Result<()> {
+        self.write(&self.data.lock().unwrap(), true)
+    }
+
+    fn write(&self, data: &LibraryState, sync: bool) -> eyre::Result<()> {
+        let tmp = self.path.with_extension("json.tmp");
+        let mut file = std::fs::File::create(&tmp)?;
+        file.write_all(&serde_json::to_vec_pretty(data)?)?;
+        if sync {
+            file.sync_all()?;
+        }
+        std::fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn state_dir_is_locked_while_open() {
+        let dir =
+            std::env::temp_dir().join(format!("storytel-sync-state-lock-{}", std::process::id()));
+        let store = StateStore::open(&dir).unwrap();
+        assert!(StateStore::open(&dir).is_err());
+        drop(store);
+        assert!(StateStore::open(&dir).is_ok());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,71 @@
+use crate::config::Storage;
+use std::path::Path;
+
+/// Total size of all files below `dir`.
+pub fn library_size(dir: &Path) -> u64 {
+    let mut total = 0;
+    let mut stack = vec![dir.to_path_buf()];
+    while let Some(d) = stack.pop() {
+        let Ok(entries) = std::fs::read_dir(&d) else {
+            continue;
+        };
+        for e in entries.flatten() {
+            match e.metadata() {
+                Ok(m) if m.is_dir() => stack.push(e.path()),
+                Ok(m) => total += m.len(),
+                Err(_) => {}
+            }
+        }
+    }
+    total
+}
+
+/// Fails when writing `needed` more bytes under `root` would eat into the
+/// free-space reserve or push the library over its size cap. `used` is the
+/// library size if already known; otherwise it is measured when a cap is set.
+pub fn check(cfg: &Storage, root: &Path, needed: u64, used: Option<u64>) -> eyre::Result<()> {
+    // the library may not exist yet on a fresh install
+    let existing = root.ancestors().find(|p| p.exists()).unwrap_or(root);
+    let free = fs4::available_space(existing)?;
+    if free < needed.saturating_add(cfg.min_free_bytes) {
+        eyre::bail!(
+            "not enough free space in {}: {free} bytes free, {needed} needed plus a reserve of {}",
+            root.display(),
+            cfg.min_free_bytes
+        );
+    }
+    if let Some(cap) = cfg.max_library_bytes {
+        let used = used.unwrap_or_else(|| library_size(root));
+        if used.saturating_add(needed) > cap {
+            eyre::bail!("library size limit reached: {used} of {cap} bytes used, {needed} needed");
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn storage(max_library_bytes: Option<u64>) -> Storage {
+        Storage {
+            min_free_bytes: 0,
+            max_library_bytes,
+        }
+    }
+
+    #[test]
+    fn missing_library_is_measured_at_its_parent() {
+        let root = std::env::temp_dir().join("storytel-sync-missing/library");
+        assert!(!root.exists());
+        check(&storage(None), &root, 1, None).unwrap();
+        check(&storage(Some(10)), &root, 10, None).unwrap();
+    }
+
+    #[test]
+    fn known_usage_counts_against_the_cap() {
+        let root = std::env::temp_dir();
+        check(&storage(Some(100)), &root, 40, Some(60)).unwrap();
+        assert!(check(&storage(Some(100)), &root, 41, Some(60)).is_err());
+    }
+}
@@ -0,0 +1,905 @@
+use crate::client_storytel_api::{self, StorytelClient};
+use crate::client_storytel_api::{BookShelf, DownloadOptions};
+use crate::config::{Config, LiveConfig, Retry};
+use crate::download::{BookLocks, is_downloaded, sanitize};
+use crate::filters::{ADD_DATE_UNKNOWN, Selection};
+use crate::health::HEALTH;
+use crate::metadata::BookMetadata;
+use crate::metrics::METRICS;
+use crate::schedule::Run;
+use crate::shelf_cache::ShelfCache;
+use crate::shutdown::{self, SHUTDOWN};
+use crate::state::{LibraryState, StateStore, book_key};
+use crate::throttle::{RateLimiter, Throttles};
+use crate::web_app::{ProgressData, fmt_bytes};
+use crate::webhooks::{self, BookInfo, Event};
+use actix_web::web;
+use chrono::{DateTime, Local};
+use serde::Serialize;
+use std::collections::HashSet;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+/// Progress of the running sync pass, or the summary of the last one.
+#[derive(Serialize, Clone, Default)]
+pub struct PassSummary {
+    pub started_at: String,
+    pub finished_at: Option<String>,
+    pub already_synced: usize,
+    pub need_sync: usize,
+    pub downloaded: usize,
+    pub failed: usize,
+    /// `author/title` of the book being downloaded right now.
+    pub current: Option<String>,
+    pub error: Option<String>,
+}
+
+/// Scheduler state shared with the web UI and API.
+#[derive(Default)]
+pub struct SyncState {
+    next_run: std::sync::Mutex<Option<DateTime<Local>>>,
+    running: AtomicBool,
+    pass: std::sync::Mutex<Option<PassSummary>>,
+    warning: std::sync::Mutex<Option<String>>,
+    wake: Notify,
+    reschedule: Notify,
+}
+
+impl SyncState {
+    pub fn next_run(&self) -> Option<DateTime<Local>> {
+        *self.next_run.lock().unwrap()
+    }
+
+    fn set_next_run(&self, at: Option<DateTime<Local>>) {
+        *self.next_run.lock().unwrap() = at;
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running.load(Ordering::Acquire)
+    }
+
+    /// Problem that stopped the last pass early, such as a full disk.
+    pub fn warning(&self) -> Option<String> {
+        self.warning.lock().unwrap().clone()
+    }
+
+    fn set_warning(&self, warning: Option<String>) {
+        *self.warning.lock().unwrap() = warning;
+    }
+
+    /// Current pass if one is running, otherwise the last finished one.
+    pub fn pass(&self) -> Option<PassSummary> {
+        self.pass.lock().unwrap().clone()
+    }
+
+    fn update_pass(&self, f: impl FnOnce(&mut PassSummary)) {
+        if let Some(p) = self.pass.lock().unwrap().as_mut() {
+            f(p);
+        }
+    }
+
+    /// Wakes the worker for an immediate pass. Returns `false` when a pass is
+    /// already running; repeated triggers before the worker wakes coalesce.
+    pub fn trigger(&self) -> bool {
+        if self.is_running() {
+            return false;
+        }
+        self.wake.notify_one();
+        true
+    }
+
+    /// Makes the worker plan its next pass again after `sync_enabled` or the
+    /// schedule changed.
+    pub fn reschedule(&self) {
+        self.reschedule.notify_one();
+    }
+
+    /// Single-flight guard: at most one pass holds the returned guard.
+    fn begin_pass(&self) -> Option<RunningPass<'_>> {
+        if self.running.swap(true, Ordering::AcqRel) {
+            return None;
+        }
+        *self.pass.lock().unwrap() = Some(PassSummary {
+            started_at: Local::now().to_rfc3339(),
+            ..PassSummary::default()
+        });
+        Some(RunningPass(self))
+    }
+
+    fn end_pass(&self, error: Option<String>) {
+        self.update_pass(|p| {
+            p.finished_at = Some(Local::now().to_rfc3339());
+            p.current = None;
+            p.error = error;
+        });
+    }
+}
+
+/// Clears [`SyncState::is_running`] when dropped, even if the pass panics.
+struct RunningPass<'a>(&'a SyncState);
+
+impl Drop for RunningPass<'_> {
+    fn drop(&mut self) {
+        self.0.running.store(false, Ordering::Release);
+    }
+}
+
+/// Downloads audio, cover and metadata sidecars of one book into `target`.
+/// The audio is verified against the book length; a failed check returns a
+/// [`crate::verify::Corrupt`] error and leaves the file in place. A missing
+/// cover or sidecar is only logged; [`fetch_extras`] runs again on later
+/// passes. Returns the size of the audio file in bytes.
+///
+/// Callers hold a [`SHUTDOWN`] guard until they have recorded the result, so
+/// a shutdown does not exit halfway through a book.
+pub async fn fetch_book<F>(
+    client: &StorytelClient,
+    opts: &DownloadOptions,
+    id: u64,
+    target: &Path,
+    cover_url: &str,
+    meta: Option<&BookMetadata>,
+    progress: F,
+) -> eyre::Result<u64>
+where
+    F: FnMut(u64, Option<u64>) + Send + 'static,
+{
+    let stream_url = client.get_stream_url(id).await?;
+    let size =
+        client_storytel_api::download_stream_with_progress(&stream_url, target, opts, progress)
+            .await?;
+    let expected = meta.and_then(|m| m.length);
+    let duration = crate::verify::check_audio_async(target.join("audio.mp3"), expected).await?;
+    tracing::debug!(
+        "fetch_book: audio verified ({}s), downloading cover {}",
+        duration.as_secs(),
+        cover_url
+    );
+    if let Err(e) = fetch_extras(cover_url, target, meta, &opts.retry).await {
+        tracing::warn!(
+            "fetch_book: cover or metadata of {} missing, retrying on the next pass: {e:#}",
+            target.display()
+        );
+    }
+    Ok(size)
+}
+
+/// Downloads the cover and writes the metadata sidecars of a book, skipping
+/// the files that are already there.
+pub async fn fetch_extras(
+    cover_url: &str,
+    target: &Path,
+    meta: Option<&BookMetadata>,
+    retry: &Retry,
+) -> eyre::Result<()> {
+    crate::download::download_cover(cover_url, target, retry).await?;
+    if let Some(meta) = meta {
+        crate::metadata::write_sidecars(meta, target).await?;
+    }
+    Ok(())
+}
+
+/// What a sync pass does with one audiobook from the shelf.
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "action", content = "reason", rename_all = "snake_case")]
+pub enum Decision {
+    Download,
+    /// Hard-linked from another account's copy with the same ISBN.
+    Link(PathBuf),
+    /// On disk but flagged corrupt by verification.
+    Redownload(String),
+    AlreadyDownloaded,
+    Filtered(String),
+}
+
+impl Decision {
+    pub fn downloads(&self) -> bool {
+        matches!(self, Self::Download | Self::Link(_) | Self::Redownload(_))
+    }
+}
+
+#[derive(Serialize)]
+pub struct PlanItem {
+    pub abook_id: u64,
+    pub author: String,
+    pub title: String,
+    pub path: PathBuf,
+    #[serde(flatten)]
+    pub decision: Decision,
+    pub estimated_bytes: Option<u64>,
+    #[serde(skip)]
+    cover_url: String,
+    #[serde(skip)]
+    meta: BookMetadata,
+}
+
+#[derive(Serialize)]
+pub struct SyncPlan {
+    pub items: Vec<PlanItem>,
+    pub already_synced: usize,
+    pub need_sync: usize,
+    pub filtered: usize,
+    /// Sum of the known download sizes; books with unknown size are not counted.
+    pub estimated_bytes: u64,
+    /// Why downloads would not start now: free space or library size limit.
+    pub paused: Option<String>,
+    /// End of the quiet hours that downloads currently wait for.
+    pub quiet_until: Option<String>,
+    /// Library size when a size limit is set, kept up to date by the pass.
+    #[serde(skip)]
+    library_used: Option<u64>,
+}
+
+/// Decides for every audiobook on `shelf` whether a sync pass downloads it,
+/// links it from another account's library, or skips it, and whether
+/// storage limits or quiet hours hold the downloads back. This is the single
+/// source of truth for both real passes and dry runs.
+fn plan(
+    shelf: &BookShelf,
+    cfg: &Config,
+    dl_dir: &Path,
+    library: &LibraryState,
+    dedupe_dirs: &[PathBuf],
+) -> eyre::Result<SyncPlan> {
+    let selection = Selection::compile(&cfg.filters)?;
+    let isbn_index = crate::dedupe::isbn_index(dedupe_dirs);
+    let mut items = Vec::new();
+    for be in &shelf.books {
+        let id = match &be.abook {
+            Some(a) => a.id,
+            None => continue,
+        };
+        let meta = BookMetadata::from_entry(id, be);
+        let author = be
+            .book
+            .authors_as_string
+            .clone()
+            .unwrap_or_else(|| "unknown".into());
+        let title = be.book.name.clone();
+        let (author_s, title_s) = (sanitize(&author), sanitize(&title));
+        let path = dl_dir.join(&author_s).join(&title_s);
+        let cover_rel = be
+            .cover
+            .as_ref()
+            .or(be.book.cover.as_ref())
+            .map_or("/images/nocover.png", String::as_str);
+
+        let decision = if let Some(reason) = selection.skip_reason(be) {
+            Decision::Filtered(reason)
+        } else if is_downloaded(dl_dir, &author_s, &title_s) {
+            match library.needs_redownload(&book_key(dl_dir, &path)) {
+                Some(c) => Decision::Redownload(c.reason.clone()),
+                None => Decision::AlreadyDownloaded,
+            }
+        } else if let Some(src) = meta
+            .isbn
+            .as_deref()
+            .and_then(|isbn| isbn_index.get(&crate::filters::normalize_isbn(isbn)))
+        {
+            Decision::Link(src.clone())
+        } else {
+            Decision::Download
+        };
+        items.push(PlanItem {
+            abook_id: id,
+            path,
+            author,
+            title,
+            decision,
+            estimated_bytes: None,
+            cover_url: format!("https://www.storytel.com{cover_rel}"),
+            meta,
+        });
+    }
+    let count = |d: fn(&Decision) -> bool| items.iter().filter(|i| d(&i.decision)).count();
+    let need_sync = count(Decision::downloads);
+
+    let library_used = cfg
+        .storage
+        .max_library_bytes
+        .map(|_| crate::storage::library_size(dl_dir));
+    let paused = (need_sync > 0)
+        .then(|| crate::storage::check(&cfg.storage, dl_dir, 0, library_used).err())
+        .flatten()
+        .map(|e| format!("{e:#}"));
+    let now = Local::now();
+    let quiet_until = cfg
+        .schedule
+        .quiet_wait(now)
+        .map(|wait| (now + wait).to_rfc3339());
+    Ok(SyncPlan {
+        already_synced: count(|d| *d == Decision::AlreadyDownloaded),
+        need_sync,
+        filtered: count(|d| matches!(d, Decision::Filtered(_))),
+        estimated_bytes: 0,
+        paused,
+        quiet_until,
+        library_used,
+        items,
+    })
+}
+
+/// Bytes charged to the rate limiter for each size estimate, so estimating
+/// a large shelf does not hammer Storytel.
+const ESTIMATE_COST: u64 = 64 * 1024;
+
+/// Computes what the next sync pass would do without writing anything.
+/// With `sizes`, download sizes are estimated from the stream's
+/// `Content-Length`, which takes two requests to Storytel per book; they are
+/// retried like downloads and paced by the given limiter.
+pub async fn dry_run(
+    client: &StorytelClient,
+    shelf: &BookShelf,
+    cfg: &Config,
+    library: &LibraryState,
+    dedupe_dirs: &[PathBuf],
+    sizes: Option<&RateLimiter>,
+) -> eyre::Result<SyncPlan> {
+    let mut plan = plan(shelf, cfg, &cfg.download_dir, library, dedupe_dirs)?;
+    let Some(limiter) = sizes else {
+        return Ok(plan);
+    };
+
+    let http = reqwest::Client::new();
+    for item in &mut plan.items {
+        // linked books are not downloaded
+        if !matches!(item.decision, Decision::Download | Decision::Redownload(_)) {
+            continue;
+        }
+        let size = async {
+            limiter.consume(ESTIMATE_COST).await;
+            let url = client.get_stream_url(item.abook_id).await?;
+            let resp = crate::retry::send(&cfg.retry, "dry_run", || http.head(&url)).await?;
+            eyre::Ok(resp.content_length())
+        }
+        .await;
+        match size {
+            Ok(size) => item.estimated_bytes = size,
+            Err(e) => tracing::debug!("dry_run: no size for id={}: {e:#}", item.abook_id),
+        }
+    }
+    plan.estimated_bytes = plan.items.iter().filter_map(|i| i.estimated_bytes).sum();
+    Ok(plan)
+}
+
+impl SyncPlan {
+    /// Human-readable report used by the `dry-run` command.
+    pub fn to_text(&self) -> String {
+        let mut out = String::new();
+        for item in &self.items {
+            let size = || {
+                item.estimated_bytes
+                    .map_or_else(|| "size unknown".into(), fmt_bytes)
+            };
+            let (action, note) = match &item.decision {
+                Decision::Download => ("DOWNLOAD", size()),
+                Decision::Link(src) => ("LINK", format!("from {}", src.display())),
+                Decision::Redownload(reason) => ("REDOWNLOAD", format!("{reason}, {}", size())),
+                Decision::AlreadyDownloaded => ("SKIP", "already downloaded".into()),
+                Decision::Filtered(reason) => ("SKIP", reason.clone()),
+            };
+            writeln!(
+                out,
+                "{action:<10} {}/{} (id={}, {note}) -> {}",
+                item.author,
+                item.title,
+                item.abook_id,
+                item.path.display()
+            )
+            .unwrap();
+        }
+        writeln!(
+            out,
+            "\n{} to download (~{}), {} already downloaded, {} filtered",
+            self.need_sync,
+            fmt_bytes(self.estimated_bytes),
+            self.already_synced,
+            self.filtered
+        )
+        .unwrap();
+        if let Some(until) = &self.quiet_until {
+            writeln!(out, "Quiet hours: downloads wait until {until}").unwrap();
+        }
+        if let Some(reason) = &self.paused {
+            writeln!(out, "Sync would pause: {reason}").unwrap();
+        }
+        out
+    }
+}
+
+/// State of one account used by sync passes, cloned into the worker and web
+/// handlers.
+#[derive(Clone)]
+pub struct SyncContext {
+    pub account: String,
+    pub client: web::Data<StorytelClient>,
+    pub cfg: web::Data<LiveConfig>,
+    pub dl_dir: web::Data<PathBuf>,
+    pub progress: ProgressData,
+    pub state: web::Data<SyncState>,
+    pub locks: web::Data<BookLocks>,
+    pub store: web::Data<StateStore>,
+    pub throttles: web::Data<Throttles>,
+    /// Libraries of the other accounts when `dedupe_by_isbn` is on.
+    pub dedupe_dirs: web::Data<Vec<PathBuf>>,
+    pub shelf: web::Data<ShelfCache>,
+}
+
+/// Runs one sync pass. Returns how long to wait before resuming when quiet
+/// hours ended the pass early.
+async fn sync_pass(ctx: &SyncContext) -> eyre::Result<Option<Duration>> {
+    let SyncContext {
+        client,
+        cfg,
+        dl_dir,
+        progress,
+        state,
+        locks,
+        store,
+        throttles,
+        dedupe_dirs,
+        shelf: shelf_cache,
+        ..
+    } = ctx;
+    // settings stay fixed for the whole pass, even across a reload
+    let cfg = cfg.get();
+
+    client.ensure_login().await?;
+    // fresh bookshelf, which also updates the cache the pages use
+    let shelf = shelf_cache.refresh(client).await?.shelf;
+    let SyncPlan {
+        items,
+        already_synced,
+        need_sync,
+        filtered,
+        paused,
+        mut library_used,
+        ..
+    } = plan(&shelf, &cfg, dl_dir, &store.get(), dedupe_dirs)?;
+    let shelf_books: HashSet<PathBuf> = items.iter().map(|i| i.path.clone()).collect();
+    let undated = items
+        .iter()
+        .filter(|i| matches!(&i.decision, Decision::Filtered(r) if r == ADD_DATE_UNKNOWN))
+        .count();
+    if undated > 0 {
+        tracing::warn!(
+            "sync_worker: skipping {undated} books whose add date Storytel does not report (filters.added_within_days)"
+        );
+    }
+
+    METRICS.set_books(
+        &ctx.account,
+        already_synced + need_sync + filtered,
+        already_synced,
+        need_sync,
+    );
+    state.update_pass(|p| {
+        p.already_synced = already_synced;
+        p.need_sync = need_sync;
+    });
+    tracing::info!(
+        "sync_worker: starting sync pass - already_synced={}, need_sync={}, filtered={}",
+        already_synced,
+        need_sync,
+        filtered
+    );
+    webhooks::emit(
+        &cfg.webhooks,
+        Event::SyncStarted {
+            already_synced,
+            need_sync,
+        },
+    );
+
+    let mut opts = cfg.download_options(&throttles.sync);
+    state.set_warning(None);
+    if let Some(reason) = &paused {
+        tracing::warn!("sync_worker: pausing sync: {reason}");
+        state.set_warning(Some(format!("Sync paused: {reason}")));
+    }
+    let (mut downloaded, mut failed) = (0, 0);
+    let mut resume_after = None;
+    for item in items {
+        if let Decision::Filtered(reason) = &item.decision {
+            tracing::debug!(
+                "sync_worker: skipping {} (id={}): {reason}",
+                item.title,
+                item.abook_id
+            );
+        }
+        if let Decision::Redownload(reason) = &item.decision {
+            tracing::info!(
+                "sync_worker: re-downloading {} (id={}): {reason}",
+                item.title,
+                item.abook_id
+            );
+        }
+        // books downloaded before whose cover or sidecars could not be saved
+        if item.decision == Decision::AlreadyDownloaded
+            && paused.is_none()
+            && !SHUTDOWN.is_stopping()
+            && let Some(_book_lock) = BookLocks::try_lock(locks, item.abook_id)
+            && let Err(e) =
+                fetch_extras(&item.cover_url, &item.path, Some(&item.meta), &cfg.retry).await
+        {
+            tracing::warn!(
+                "sync_worker: cover or metadata of {} missing: {e:#}",
+                item.path.display()
+            );
+        }
+        if !item.decision.downloads() || paused.is_some() {
+            continue;
+        }
+        let PlanItem {
+            abook_id: id,
+            author,
+            title,
+            path: target,
+            decision,
+            cover_url,
+            meta,
+            ..
+        } = item;
+
+        // ending the pass keeps it from holding a book lock and the
+        // running flag through the night
+        if let Some(wait) = cfg.schedule.quiet_wait(Local::now()) {
+            tracing::info!(
+                "sync_worker: quiet hours, ending the pass and resuming in {} min",
+                wait.as_secs() / 60
+            );
+            resume_after = Some(wait);
+            break;
+        }
+        let Some(_book_lock) = BookLocks::try_lock(locks, id) else {
+            tracing::info!("sync_worker: {author}/{title} is already being downloaded, skipping");
+            continue;
+        };
+        // may have been fetched on demand since the plan was made
+        if target.join("audio.mp3").exists()
+            && store
+                .get()
+                .needs_redownload(&book_key(dl_dir, &target))
+                .is_none()
+        {
+            continue;
+        }
+
+        // held until the result is recorded
+        let Some(_tracked) = SHUTDOWN.track() else {
+            tracing::info!("sync_worker: shutting down, ending the pass");
+            break;
+        };
+        // the library grows during the pass; the plan only covered its start
+        if let Err(e) = crate::storage::check(&cfg.storage, dl_dir, 0, library_used) {
+            tracing::warn!("sync_worker: pausing sync: {e:#}");
+            state.set_warning(Some(format!("Sync paused: {e:#}")));
+            break;
+        }
+
+        let info = BookInfo {
+            abook_id: id,
+            author: author.clone(),
+            title: title.clone(),
+            path: target.clone(),
+        };
+
+        if let Decision::Link(src) = &decision {
+            match crate::dedupe::link_book(src, &target) {
+                Ok(size) => {
+                    library_used = library_used.map(|u| u + size);
+                    tracing::info!(
+                        "sync_worker: linked {author}/{title} from {}",
+                        src.display()
+                    );
+                    downloaded += 1;
+                    state.update_pass(|p| p.downloaded = downloaded);
+                    webhooks::emit(&cfg.webhooks, Event::DownloadFinished { book: info, size });
+                    continue;
+                }
+                Err(e) => tracing::warn!(
+                    "sync_worker: cannot link {author}/{title} from {}, downloading: {e:#}",
+                    src.display()
+                ),
+            }
+        }
+
+        tracing::info!("sync_worker: downloading {author}/{title} (id={id})");
+        state.update_pass(|p| p.current = Some(format!("{author}/{title}")));
+        webhooks::emit(&cfg.webhooks, Event::DownloadStarted { book: info.clone() });
+
+        let mut last = Instant::now();
+        let prog_inner = progress.clone();
+        let author_clone = author.clone();
+        let title_clone = title.clone();
+
+        opts.library_used = library_used;
+        let result = fetch_book(
+            client,
+            &opts,
+            id,
+            &target,
+            &cover_url,
+            Some(&meta),
+            move |done, total| {
+                if let Ok(mut map) = prog_inner.try_lock() {
+                    map.insert(id, (done, total));
+                }
+                if last.elapsed().as_secs() >= 60 {
+                    last = Instant::now();
+                    tracing::info!(
+                        "[sync] {author_clone}/{title_clone}  {} / {}",
+                        fmt_bytes(done),
+                        total.map_or_else(|| "?".into(), fmt_bytes)
+                    );
+                }
+            },
+        )
+        .await;
+        progress.lock().await.remove(&id);
+        crate::verify::record(store, dl_dir, &target, &result);
+
+        if shutdown::is_interrupted(&result) {
+            tracing::info!("sync_worker: {author}/{title} will continue after a restart");
+            break;
+        }
+        match result {
+            Ok(size) => {
+                library_used = library_used.map(|u| u + size);
+                tracing::info!("sync_worker: finished {author}/{title}");
+                downloaded += 1;
+                state.update_pass(|p| p.downloaded = downloaded);
+                webhooks::emit(&cfg.webhooks, Event::DownloadFinished { book: info, size });
+            }
+            Err(e) => {
+                tracing::error!("sync_worker: {author}/{title} failed: {e:#}");
+                failed += 1;
+                state.update_pass(|p| p.failed = failed);
+                webhooks::emit(
+                    &cfg.webhooks,
+                    Event::DownloadFailed {
+                        book: info,
+                        error: format!("{e:#}"),
+                    },
+                );
+            }
+        }
+    }
+
+    // one scan for the whole pass rather than one per book
+    if downloaded > 0 {
+        crate::media_server::notify_all(&cfg.media_servers).await;
+    }
+    if let Err(e) = crate::prune::run(&cfg.prune, dl_dir, &shelf_books, store) {
+        tracing::error!("sync_worker: orphan detection failed: {e:#}");
+    }
+
+    METRICS.set_books(
+        &ctx.account,
+        already_synced + need_sync + filtered,
+        already_synced + downloaded,
+        need_sync - downloaded,
+    );
+    if failed == 0 {
+        METRICS.sync_succeeded(&ctx.account);
+    }
+    HEALTH.set_sync_failed(&ctx.account, failed > 0);
+    tracing::info!("sync_worker: sync pass finished - downloaded={downloaded}, failed={failed}");
+    webhooks::emit(
+        &cfg.webhooks,
+        Event::SyncFinished {
+            already_synced,
+            downloaded,
+            failed,
+        },
+    );
+    Ok(resume_after)
+}
+
+/// First delay between background login attempts, doubled after every
+/// failure up to [`LOGIN_RETRY_MAX`].
+const LOGIN_RETRY_MIN: Duration = Duration::from_secs(60);
+const LOGIN_RETRY_MAX: Duration = Duration::from_secs(30 * 60);
+
+/// Keeps logging in after a failed start-up login until it succeeds. The
+/// web UI serves the local library meanwhile.
+pub async fn login_worker(ctx: SyncContext) {
+    let mut delay = LOGIN_RETRY_MIN;
+    loop {
+        tokio::time::sleep(delay).await;
+        match ctx.client.ensure_login().await {
+            Ok(()) => {
+                tracing::info!("login_worker: logged in as account {}", ctx.account);
+                return;
+            }
+            Err(e) => {
+                delay = (delay * 2).min(LOGIN_RETRY_MAX);
+                tracing::warn!(
+                    "login_worker: login for account {} failed, next try in {} min: {e:#}",
+                    ctx.account,
+                    delay.as_secs() / 60
+                );
+            }
+        }
+    }
+}
+
+/// Next regular pass after `after`, or `None` with background sync off.
+fn next_scheduled(cfg: &Config, after: DateTime<Local>) -> Option<Run> {
+    if !cfg.sync_enabled {
+        return None;
+    }
+    cfg.schedule
+        .next_run(after, Local::now())
+        .inspect_err(|e| tracing::error!("sync_worker: cannot schedule next pass: {e:#}"))
+        .ok()
+}
+
+/// Runs sync passes on the configured schedule and whenever
+/// [`SyncState::trigger`] is called. With `sync_enabled = false` only manual
+/// triggers start a pass.
+pub async fn sync_worker(ctx: SyncContext) {
+    let state = &ctx.state;
+    let cfg = ctx.cfg.get();
+    let mut next = if cfg.sync_enabled {
+        cfg.schedule
+            .first_run(Local::now())
+            .inspect_err(|e| tracing::error!("sync_worker: cannot schedule first pass: {e:#}"))
+            .ok()
+    } else {
+        None
+    };
+    if let Some(run) = next {
+        tracing::info!("sync_worker: first sync pass at {}", run.at.to_rfc3339());
+    }
+
+    loop {
+        state.set_next_run(next.map(|r| r.at));
+        let timer = async move {
+            match next.map(|r| (r.at - Local::now()).to_std()) {
+                Some(Ok(wait)) => tokio::time::sleep(wait).await,
+                Some(Err(_)) => {} // already due
+                None => std::future::pending().await,
+            }
+        };
+        let woke = tokio::select! {
+            () = timer => Some(false),
+            () = state.wake.notified() => Some(true),
+            () = state.reschedule.notified() => None,
+        };
+        let Some(manual) = woke else {
+            next = next_scheduled(&ctx.cfg.get(), Local::now());
+            match next {
+                Some(run) => tracing::info!("sync_worker: rescheduled to {}", run.at.to_rfc3339()),
+                None => tracing::info!("sync_worker: background sync disabled"),
+            }
+            continue;
+        };
+
+        let started = Local::now();
+        if manual {
+            tracing::info!("sync_worker: manual sync pass triggered");
+        }
+        // the pass runs in its own task so a panic cannot take the worker down,
+        // but it is awaited so passes never overlap
+        let pass = {
+            let ctx = ctx.clone();
+            tokio::spawn(async move {
+                let state = &ctx.state;
+                let Some(_running) = state.begin_pass() else {
+                    tracing::warn!("sync_worker: another sync pass is running, skipping");
+                    return None;
+                };
+                let res = sync_pass(&ctx).await;
+                if let Err(e) = &res {
+                    tracing::error!("sync_worker: sync pass failed: {e:#}");
+                    HEALTH.set_sync_failed(&ctx.account, true);
+                }
+                let resume_after = res.as_ref().ok().copied().flatten();
+                state.end_pass(res.err().map(|e| format!("{e:#}")));
+                resume_after
+            })
+        };
+        let resume_after = pass.await.unwrap_or_else(|e| {
+            tracing::error!("sync_worker: sync pass aborted: {e}");
+            HEALTH.set_sync_failed(&ctx.account, true);
+            None
+        });
+
+        // manual passes do not shift the regular schedule
+        if !manual {
+            // a pass that jitter started early still belongs to its slot
+            let after = next.map_or(started, |r| started.max(r.slot));
+            next = next_scheduled(&ctx.cfg.get(), after);
+            if let Some(run) = next {
+                tracing::info!("sync_worker: next sync pass at {}", run.at.to_rfc3339());
+            }
+        }
+        // the books quiet hours held back are fetched once they are over
+        if let Some(wait) = resume_after {
+            let at = Local::now() + chrono::Duration::from_std(wait).unwrap_or_default();
+            if next.is_none_or(|r| at < r.at) {
+                tracing::info!("sync_worker: resuming the sync pass at {}", at.to_rfc3339());
+                next = Some(Run { slot: at, at });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn plan_covers_links_storage_and_quiet_hours() {
+        let root = std::env::temp_dir().join(format!("storytel-sync-plan-{}", std::process::id()));
+        let (dl_dir, other) = (root.join("me"), root.join("other"));
+        let write = |dir: &Path, file: &str, data: &str| {
+            std::fs::create_dir_all(dir).unwrap();
+            std::fs::write(dir.join(file), data).unwrap();
+        };
+        write(&dl_dir.join("A/Have"), "audio.mp3", "mp3");
+        write(&other.join("B/Shared"), "audio.mp3", "mp3");
+        write(
+            &other.join("B/Shared"),
+            "metadata.json",
+            r#"{"isbn": "978-1"}"#,
+        );
+
+        let shelf: BookShelf = serde_json::from_value(json!({"books": [
+            {"abook": {"id": 1}, "book": {"name": "Have", "authorsAsString": "A"}},
+            {"abook": {"id": 2, "isbn": "9781"}, "book": {"name": "Shared", "authorsAsString": "B"}},
+            {"abook": {"id": 3}, "book": {"name": "New", "authorsAsString": "C"}},
+        ]}))
+        .unwrap();
+        // quiet hours around the current time
+        let now = Local::now();
+        let hour = chrono::Duration::hours(1);
+        let quiet_hours = format!(
+            "{}-{}",
+            (now - hour).format("%H:%M"),
+            (now + hour).format("%H:%M")
+        );
+        let mut cfg: Config = serde_json::from_value(json!({
+            "download_dir": dl_dir,
+            "schedule": {"quiet_hours": quiet_hours},
+            "storage": {"min_free_bytes": 0},
+        }))
+        .unwrap();
+        let library = LibraryState::default();
+
+        let p = plan(
+            &shelf,
+            &cfg,
+            &dl_dir,
+            &library,
+            std::slice::from_ref(&other),
+        )
+        .unwrap();
+        let decisions: Vec<&Decision> = p.items.iter().map(|i| &i.decision).collect();
+        assert_eq!(
+            decisions,
+            [
+                &Decision::AlreadyDownloaded,
+                &Decision::Link(other.join("B/Shared")),
+                &Decision::Download,
+            ]
+        );
+        assert_eq!((p.already_synced, p.need_sync), (1, 2));
+        assert_eq!(p.paused, None);
+        assert!(p.quiet_until.is_some());
+
+        // without dedupe the shared book is downloaded; the library is full
+        cfg.storage.max_library_bytes = Some(2);
+        let p = plan(&shelf, &cfg, &dl_dir, &library, &[]).unwrap();
+        assert_eq!(p.items[1].decision, Decision::Download);
+        assert_eq!(p.library_used, Some(3));
+        assert!(p.paused.unwrap().contains("library size limit"));
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
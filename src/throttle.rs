@@ -0,0 +1,94 @@
+use crate::config::Bandwidth;
+use crate::schedule::TimeWindow;
+use chrono::Local;
+use eyre::WrapErr;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+/// Token bucket shared by all downloads of one class (sync or on-demand).
+/// Holds at most one second worth of tokens. The limit can be changed while
+/// downloads are running.
+pub struct RateLimiter {
+    /// Bytes per second, `None` for no limit, and the daily window without limit.
+    limit: RwLock<(Option<f64>, Option<TimeWindow>)>,
+    bucket: Mutex<(f64, Instant)>,
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_sec: Option<u64>, unthrottled: Option<TimeWindow>) -> Self {
+        let rate = bytes_per_sec.map(|r| r as f64);
+        Self {
+            limit: RwLock::new((rate, unthrottled)),
+            bucket: Mutex::new((rate.unwrap_or(0.0), Instant::now())),
+        }
+    }
+
+    pub fn set_limit(&self, bytes_per_sec: Option<u64>, unthrottled: Option<TimeWindow>) {
+        *self.limit.write().unwrap() = (bytes_per_sec.map(|r| r as f64), unthrottled);
+    }
+
+    /// Waits until `bytes` may be passed on.
+    pub async fn consume(&self, bytes: u64) {
+        let (rate, unthrottled) = *self.limit.read().unwrap();
+        let Some(rate) = rate else {
+            return;
+        };
+        if unthrottled.is_some_and(|w| w.contains(Local::now().time())) {
+            return;
+        }
+        let wait = {
+            let mut bucket = self.bucket.lock().await;
+            let (tokens, last) = &mut *bucket;
+            let now = Instant::now();
+            *tokens = (*tokens + now.duration_since(*last).as_secs_f64() * rate).min(rate);
+            *last = now;
+            *tokens -= bytes as f64;
+            (*tokens < 0.0).then(|| Duration::from_secs_f64(-*tokens / rate))
+        };
+        if let Some(wait) = wait {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// Limiters for background sync and on-demand downloads.
+pub struct Throttles {
+    pub sync: Arc<RateLimiter>,
+    pub on_demand: Arc<RateLimiter>,
+}
+
+impl Default for Throttles {
+    fn default() -> Self {
+        Self {
+            sync: Arc::new(RateLimiter::new(None, None)),
+            on_demand: Arc::new(RateLimiter::new(None, None)),
+        }
+    }
+}
+
+fn parse_window(cfg: &Bandwidth) -> eyre::Result<Option<TimeWindow>> {
+    cfg.unthrottled_hours
+        .as_deref()
+        .map(TimeWindow::parse)
+        .transpose()
+        .wrap_err("bandwidth.unthrottled_hours")
+}
+
+impl Throttles {
+    pub fn new(cfg: &Bandwidth) -> eyre::Result<Self> {
+        let window = parse_window(cfg)?;
+        Ok(Self {
+            sync: Arc::new(RateLimiter::new(cfg.sync_bytes_per_sec, window)),
+            on_demand: Arc::new(RateLimiter::new(cfg.download_bytes_per_sec, window)),
+        })
+    }
+
+    /// Applies new limits, including to downloads already running.
+    pub fn update(&self, cfg: &Bandwidth) -> eyre::Result<()> {
+        let window = parse_window(cfg)?;
+        self.sync.set_limit(cfg.sync_bytes_per_sec, window);
+        self.on_demand.set_limit(cfg.download_bytes_per_sec, window);
+        Ok(())
+    }
+}
@@ -0,0 +1,140 @@
+use crate::client_storytel_api::BookShelf;
+use crate::download::sanitize;
+use crate::state::{StateStore, book_key};
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Downloaded audio failed verification; the book is re-queued.
+#[derive(Debug)]
+pub struct Corrupt(pub String);
+
+impl fmt::Display for Corrupt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "corrupt audio: {}", self.0)
+    }
+}
+
+impl std::error::Error for Corrupt {}
+
+/// Allowed difference between the measured and the advertised duration.
+fn tolerance(expected: Duration) -> Duration {
+    (expected / 50).max(Duration::from_secs(60))
+}
+
+/// Whether `measured` agrees with the Storytel book length. Storytel does not
+/// document the unit of `length`; it is read as milliseconds, but a length
+/// that only matches as seconds is accepted too, so a wrong unit cannot flag
+/// good downloads as corrupt.
+fn length_matches(measured: Duration, length: u64) -> bool {
+    let close = |expected: Duration| measured.abs_diff(expected) <= tolerance(expected);
+    if close(Duration::from_millis(length)) {
+        return true;
+    }
+    if close(Duration::from_secs(length)) {
+        tracing::warn!("verify: book length {length} only matches the audio as seconds");
+        return true;
+    }
+    false
+}
+
+/// Parses the MPEG frames of `audio` and compares the resulting duration with
+/// `expected_ms`, the book length reported by Storytel in milliseconds.
+pub fn check_audio(audio: &Path, expected_ms: Option<u64>) -> eyre::Result<Duration> {
+    let scan = crate::mp3::scan(audio)?;
+    let size = std::fs::metadata(audio)?.len();
+    if scan.frames == 0 {
+        return Err(Corrupt("no MPEG audio frames found".into()).into());
+    }
+    if scan.truncated {
+        return Err(Corrupt("last audio frame is truncated".into()).into());
+    }
+    if scan.junk_bytes > size / 100 {
+        return Err(Corrupt(format!(
+            "{} bytes are not valid audio frames",
+            scan.junk_bytes
+        ))
+        .into());
+    }
+    if let Some(ms) = expected_ms.filter(|ms| *ms > 0)
+        && !length_matches(scan.duration, ms)
+    {
+        return Err(Corrupt(format!(
+            "duration {}s differs from the expected {}s",
+            scan.duration.as_secs(),
+            Duration::from_millis(ms).as_secs()
+        ))
+        .into());
+    }
+    Ok(scan.duration)
+}
+
+/// Runs [`check_audio`] off the async runtime.
+pub async fn check_audio_async(audio: PathBuf, expected_ms: Option<u64>) -> eyre::Result<Duration> {
+    tokio::task::spawn_blocking(move || check_audio(&audio, expected_ms)).await?
+}
+
+/// Updates the corrupt flag of the book in `book_dir` from a download result.
+pub fn record<T>(store: &StateStore, dl_dir: &Path, book_dir: &Path, res: &eyre::Result<T>) {
+    let key = book_key(dl_dir, book_dir);
+    let update = match res {
+        Ok(_) if !store.get().corrupt.contains_key(&key) => return,
+        Ok(_) => store.update(|s| drop(s.corrupt.remove(&key))),
+        Err(e) => match e.downcast_ref::<Corrupt>() {
+            Some(c) => store.update(|s| s.mark_corrupt(&key, &c.0)),
+            None => return,
+        },
+    };
+    if let Err(e) = update {
+        tracing::error!("verify: cannot update library state: {e:#}");
+    }
+}
+
+/// Storytel lengths of the shelf's audiobooks, keyed by book directory.
+pub fn expected_lengths(shelf: &BookShelf, dl_dir: &Path) -> HashMap<PathBuf, u64> {
+    shelf
+        .books
+        .iter()
+        .filter_map(|be| {
+            let length = be.length.or(be.book.length)?;
+            let author = be.book.authors_as_string.as_deref().unwrap_or("unknown");
+            let path = dl_dir.join(sanitize(author)).join(sanitize(&be.book.name));
+            Some((path, length))
+        })
+        .collect()
+}
+
+/// Checks every book in the library, flagging failures as corrupt so the
+/// next sync pass downloads them again. `lengths` maps book directories to
+/// the Storytel length in milliseconds where known.
+pub fn verify_library(
+    dl_dir: &Path,
+    lengths: &HashMap<PathBuf, u64>,
+    store: &StateStore,
+) -> eyre::Result<Vec<(String, eyre::Result<Duration>)>> {
+    let mut report = Vec::new();
+    for book in crate::prune::scan_local(dl_dir)? {
+        let res = check_audio(&book.join("audio.mp3"), lengths.get(&book).copied());
+        record(store, dl_dir, &book, &res);
+        report.push((book_key(dl_dir, &book), res));
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn length_is_compared_in_either_unit() {
+        let hour = Duration::from_secs(3600);
+        assert!(length_matches(hour, 3_600_000));
+        assert!(length_matches(hour + Duration::from_secs(60), 3_600_000));
+        assert!(length_matches(hour, 3600));
+        // two percent, at least a minute
+        assert!(!length_matches(hour, 3_500_000));
+        assert!(!length_matches(hour / 2, 3_600_000));
+        assert!(!length_matches(hour / 2, 3600));
+    }
+}
@@ -1,21 +1,33 @@
-use crate::client_storytel_api::{self, ClientData};
-use crate::config::Config;
+use crate::client_storytel_api::StorytelClient;
+use crate::config::{Config, LiveConfig, PrunePolicy};
+use crate::download::{BookLocks, sanitize};
+use crate::health::HEALTH;
+use crate::library;
+use crate::metadata::BookMetadata;
+use crate::metrics::METRICS;
+use crate::reload::{self, ReloadReport, Reloader};
+use crate::shelf_cache::{CachedShelf, ShelfCache};
+use crate::shutdown::{self, SHUTDOWN};
+use crate::state::{QueuedDownload, StateStore, book_key};
+use crate::sync::{self, SyncContext, SyncState, fetch_book};
+use crate::throttle::Throttles;
+use crate::webhooks::{self, BookInfo, Event};
 use actix_web::http::header;
-use actix_web::{App, HttpResponse, HttpServer, Responder, web};
-use rand::{Rng, rng};
+use actix_web::{App, HttpRequest, HttpResponse, HttpServer, Responder, web};
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::convert::TryFrom;
 use std::fmt::Write;
 use std::path::PathBuf;
-use std::time::Duration;
-use std::time::Instant;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::sync::Mutex;
 
 type ProgressStatus = (u64, Option<u64>);
 type ProgressMap = HashMap<u64, ProgressStatus>;
-type ProgressData = web::Data<Mutex<ProgressMap>>;
+pub type ProgressData = web::Data<Mutex<ProgressMap>>;
 
-fn fmt_bytes(mut bytes: u64) -> String {
+pub fn fmt_bytes(mut bytes: u64) -> String {
     const UNITS: [&str; 7] = ["B", "KiB", "MiB", "GiB", "TiB", "PiB", "EiB"];
     let mut idx = 0;
     while bytes >= 1024 && idx < UNITS.len() - 1 {
@@ -36,138 +48,184 @@ fn fmt_eta(secs: u64) -> String {
     }
 }
 
-async fn sync_worker(
-    client: web::Data<Mutex<ClientData>>,
-    dl_dir: web::Data<PathBuf>,
-    progress: ProgressData,
-) {
-    // initial delay - 10 min
-    tokio::time::sleep(Duration::from_secs(600)).await;
+/// Per-account state; handlers pick one with the `account` query parameter.
+pub struct Accounts(Vec<SyncContext>);
 
-    loop {
-        // run one full sync in a blocking thread
-        let client = client.clone();
-        let dl_dir = dl_dir.clone();
-        let progress = progress.clone();
-        tokio::spawn(async move {
-            let mut cd = client.lock().await;
-
-            // fresh bookshelf
-            let shelf = client_storytel_api::get_bookshelf(&mut cd).await.unwrap();
-
-            let (mut already_synced, mut need_sync) = (0, 0);
-            for be in &shelf.books {
-                if be.abook.is_some() {
-                    let author = be.book.authors_as_string.as_deref().unwrap_or("unknown");
-                    let title = &be.book.name;
-                    let sanitize = |s: &str| s.replace(['/', '\\'], "_");
-                    if crate::download::is_downloaded(&dl_dir, &sanitize(author), &sanitize(title))
-                    {
-                        already_synced += 1;
-                    } else {
-                        need_sync += 1;
-                    }
-                }
-            }
-            tracing::info!(
-                "sync_worker: starting sync pass - already_synced={}, need_sync={}",
-                already_synced,
-                need_sync
-            );
+#[derive(Deserialize)]
+struct AccountQuery {
+    /// Defaults to the first account.
+    account: Option<String>,
+}
 
-            for be in shelf.books {
-                let id = match be.abook {
-                    Some(a) => a.id,
-                    None => continue,
-                };
-
-                // own the pieces we will move into the download closure
-                let author: String = be
-                    .book
-                    .authors_as_string
-                    .unwrap_or_else(|| "unknown".into());
-                let title: String = be.book.name;
-                let sanitize = |s: &str| s.replace(['/', '\\'], "_");
-                let author_s = sanitize(&author);
-                let title_s = sanitize(&title);
-
-                let cover_rel = be
-                    .cover
-                    .as_ref()
-                    .or(be.book.cover.as_ref())
-                    .map_or("/images/nocover.png", String::as_str);
-                let cover_url = format!("https://www.storytel.com{cover_rel}");
-
-                if crate::download::is_downloaded(&dl_dir, &author_s, &title_s) {
-                    continue; // already there
-                }
+impl Accounts {
+    fn get(&self, q: &AccountQuery) -> Option<&SyncContext> {
+        match &q.account {
+            Some(name) => self.0.iter().find(|c| c.account == *name),
+            None => self.0.first(),
+        }
+    }
+
+    /// Query string selecting `ctx`; empty with a single account.
+    fn query(&self, ctx: &SyncContext) -> String {
+        if self.0.len() > 1 {
+            format!("?account={}", ctx.account)
+        } else {
+            String::new()
+        }
+    }
+}
 
-                tracing::info!("sync_worker: downloading {author}/{title} (id={id})");
+fn unknown_account() -> HttpResponse {
+    HttpResponse::NotFound().body("unknown account")
+}
 
-                // obtain stream url (needs &mut cd)
-                let stream = client_storytel_api::get_stream_url(&mut cd, id)
-                    .await
-                    .unwrap();
-                drop(cd); // release lock during long download
-
-                let target = dl_dir.join(&author_s).join(&title_s);
-                let mut last = Instant::now();
-                let prog_inner = progress.clone();
-
-                let author_clone = author.clone();
-                let title_clone = title.clone();
-
-                client_storytel_api::download_stream_with_progress(
-                    &stream,
-                    &target,
-                    move |done, total| {
-                        if let Ok(mut map) = prog_inner.try_lock() {
-                            map.insert(id, (done, total));
-                        }
-                        if last.elapsed().as_secs() >= 60 {
-                            last = Instant::now();
-                            tracing::info!(
-                                "[sync] {author_clone}/{title_clone}  {} / {}",
-                                fmt_bytes(done),
-                                total.map_or_else(|| "?".into(), fmt_bytes)
-                            );
-                        }
-                    },
-                )
-                .await
-                .unwrap();
-                crate::download::download_cover(&cover_url, &target)
-                    .await
-                    .unwrap();
-                tracing::info!("sync_worker: finished {author}/{title}");
-                progress.lock().await.remove(&id);
+/// Everything needed to serve one Storytel account.
+pub struct AccountInit {
+    pub name: String,
+    pub cfg: Config,
+    pub client: StorytelClient,
+    pub store: StateStore,
+}
 
-                // reacquire client lock for next book
-                cd = client.lock().await;
+/// Status line with the current/last pass, next run and a "Sync now" button.
+fn sync_banner(state: &SyncState, query: &str) -> String {
+    let mut parts = Vec::new();
+    match state.pass() {
+        Some(p) if state.is_running() => {
+            parts.push(format!(
+                "Sync running: {} of {} downloaded, {} failed",
+                p.downloaded, p.need_sync, p.failed
+            ));
+            if let Some(current) = p.current {
+                parts.push(format!("now: {current}"));
             }
-        });
+        }
+        Some(p) => {
+            parts.push(format!(
+                "Last sync: {} downloaded, {} failed",
+                p.downloaded, p.failed
+            ));
+            if let Some(err) = p.error {
+                parts.push(format!("error: {err}"));
+            }
+        }
+        None => {}
+    }
+    if let Some(next) = state.next_run() {
+        parts.push(format!("Next sync: {}", next.format("%Y-%m-%d %H:%M")));
+    }
+    if let Some(warning) = state.warning() {
+        parts.push(format!(r#"<span class="warning">{warning}</span>"#));
+    }
+    let button = if state.is_running() {
+        r#"<button disabled>Syncing&hellip;</button>"#
+    } else {
+        &format!(
+            r#"<form method="post" action="/sync{query}"><button type="submit">Sync now</button></form>"#
+        )
+    };
+    format!(
+        r#"<div class="sync">{}{button}</div>"#,
+        parts.join(" &middot; ")
+    )
+}
 
-        // sleep 24 h +/- 2 h jitter
-        let base: i32 = 86_400; // 24 h
-        let jitter: i32 = rng().random_range(-7_200..=7_200); // +/-2 h
-        let delay = u64::try_from(base + jitter).expect("always positive");
-        tokio::time::sleep(Duration::from_secs(delay)).await;
+/// Age of the shown bookshelf with a "Refresh" button; warns when Storytel
+/// could not be reached and only local books are shown.
+fn shelf_banner(cached: &eyre::Result<CachedShelf>, logged_in: bool, query: &str) -> String {
+    let mut parts = Vec::new();
+    if !logged_in {
+        parts.push(
+            r#"<span class="warning">Cannot log in to Storytel, retrying in the background</span>"#
+                .to_owned(),
+        );
+    }
+    match cached {
+        Ok(cached) => {
+            if cached.stale && logged_in {
+                parts.push(r#"<span class="warning">Storytel is unreachable</span>"#.to_owned());
+            }
+            let fetched = DateTime::from_timestamp(cached.fetched_at, 0)
+                .map(|t| t.with_timezone(&Local).format("%Y-%m-%d %H:%M").to_string())
+                .unwrap_or_default();
+            parts.push(format!("Bookshelf as of {fetched}"));
+        }
+        Err(e) => {
+            if logged_in {
+                parts.push(format!(
+                    r#"<span class="warning">Storytel is unreachable: {e}</span>"#
+                ));
+            }
+            parts.push("showing downloaded books only".to_owned());
+        }
     }
+    format!(
+        r#"<div class="sync">{}<form method="post" action="/refresh{query}"><button type="submit">Refresh</button></form></div>"#,
+        parts.join(" &middot; ")
+    )
+}
+
+/// Problems of the last config reload: a rejected file, failed logins and
+/// settings that wait for a restart. Empty when there are none.
+fn reload_banner(report: Option<ReloadReport>) -> String {
+    let Some(report) = report else {
+        return String::new();
+    };
+    let mut parts = Vec::new();
+    if let Some(err) = &report.error {
+        parts.push(format!(
+            r#"<span class="warning">Config reload failed: {err}</span>"#
+        ));
+    }
+    parts.extend(
+        report
+            .warnings
+            .iter()
+            .map(|w| format!(r#"<span class="warning">{w}</span>"#)),
+    );
+    if !report.restart_required.is_empty() {
+        parts.push(format!(
+            "Restart needed to apply: {}",
+            report.restart_required.join(", ")
+        ));
+    }
+    if parts.is_empty() {
+        return String::new();
+    }
+    format!(r#"<div class="sync">{}</div>"#, parts.join(" &middot; "))
 }
 
 async fn list(
-    data: web::Data<Mutex<ClientData>>,
-    download_dir: web::Data<PathBuf>,
-    progress: ProgressData,
+    q: web::Query<AccountQuery>,
+    accounts: web::Data<Accounts>,
+    reloader: web::Data<Reloader>,
 ) -> impl Responder {
-    // fetch bookshelf on a blocking thread
-    let bookshelf = {
-        let mut cd = data.lock().await;
-        match client_storytel_api::get_bookshelf(&mut cd).await {
-            Ok(bs) => bs,
-            Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
-        }
+    let Some(ctx) = accounts.get(&q) else {
+        return unknown_account();
+    };
+    let SyncContext {
+        client,
+        cfg,
+        dl_dir: download_dir,
+        progress,
+        state: sync_state,
+        store,
+        shelf,
+        ..
+    } = ctx;
+    let cfg = &cfg.get();
+    let query = accounts.query(ctx);
+    // without a login only the cached shelf is shown; with neither the
+    // downloaded books are listed from disk
+    let logged_in = client.is_logged_in();
+    let cached = if logged_in {
+        shelf.get(client).await
+    } else {
+        shelf
+            .peek()
+            .ok_or_else(|| eyre::eyre!("not logged in and no cached bookshelf"))
     };
+    let bookshelf = cached.as_ref().ok().map(|c| c.shelf.clone());
 
     let mut html = String::from(
         r#"
@@ -190,6 +248,54 @@ async fn list(
     color: #2c3e50; /* Darker heading color */
     margin-bottom: 30px;
  }
+ .accounts {
+    text-align: center;
+    margin: -20px 0 25px;
+ }
+ .accounts a {
+    margin: 0 8px;
+    color: #007bff;
+    text-decoration: none;
+ }
+ .accounts a.current {
+    color: #2c3e50;
+    font-weight: 600;
+ }
+ .sync {
+    text-align: center;
+    font-size: 13px;
+    color: #777;
+    margin: -20px 0 20px;
+ }
+ .sync .warning {
+    color: #c0392b;
+    font-weight: 600;
+ }
+ .sync form {
+    display: inline;
+    margin-left: 10px;
+ }
+ .sync button {
+    padding: 4px 10px;
+    font-size: 12px;
+    margin-left: 10px;
+ }
+ .sync form button {
+    margin-left: 0;
+ }
+ h2 {
+    text-align: center;
+    color: #2c3e50;
+    margin-top: 40px;
+ }
+ .orphans {
+    max-width: 800px;
+    margin: 0 auto;
+    font-size: 14px;
+ }
+ .orphans span {
+    color: #888;
+ }
  .books {
     display: flex;
     flex-wrap: wrap;
@@ -261,6 +367,16 @@ async fn list(
     color: #666666;
     cursor: default;
  }
+ a.button {
+    display: inline-block;
+    padding: 8px 16px;
+    border-radius: 4px;
+    background: #28a745;
+    color: #fff;
+    font-size: 14px;
+    font-weight: 600;
+    text-decoration: none;
+ }
 
  /* Responsive adjustments */
  @media (max-width: 768px) {
@@ -294,11 +410,34 @@ async fn list(
 </head>
 <body>
 <h1>Bookshelf</h1>
-<div class="books">
 "#,
     );
 
-    for book_entry in &bookshelf.books {
+    if accounts.0.len() > 1 {
+        html.push_str(r#"<div class="accounts">"#);
+        for other in &accounts.0 {
+            let class = if other.account == ctx.account {
+                "current"
+            } else {
+                ""
+            };
+            write!(
+                &mut html,
+                r#"<a class="{class}" href="/{}">{}</a>"#,
+                accounts.query(other),
+                other.account
+            )
+            .unwrap();
+        }
+        html.push_str("</div>");
+    }
+    html.push_str(&sync_banner(sync_state, &query));
+    html.push_str(&shelf_banner(&cached, logged_in, &query));
+    html.push_str(&reload_banner(reloader.last()));
+    html.push_str(r#"<div class="books">"#);
+
+    let library = store.get();
+    for book_entry in bookshelf.iter().flat_map(|s| &s.books) {
         let name = &book_entry.book.name;
         let author = book_entry.book.authors_as_string.as_deref().unwrap_or("");
         let isbn = book_entry
@@ -310,7 +449,6 @@ async fn list(
         let id = book_entry.abook.as_ref().map(|a| a.id);
 
         // sanitise for file-system use
-        let sanitize = |s: &str| s.replace(['/', '\\'], "_");
         let author_s = sanitize(author);
         let title_s = sanitize(name);
 
@@ -324,16 +462,36 @@ async fn list(
 
         let downloading = progress.lock().await.get(&id.unwrap_or(0)).copied();
         let downloaded =
-            id.is_some_and(|_| crate::download::is_downloaded(&download_dir, &author_s, &title_s));
+            id.is_some_and(|_| crate::download::is_downloaded(download_dir, &author_s, &title_s));
 
         let btn = if let Some((done, total)) = downloading {
             let pct = total.map_or(0, |tot| 100 * done / tot);
             format!(r#"<button disabled>Downloading {pct}%</button>"#)
+        } else if id.is_some_and(|id| library.queue.contains_key(&id)) && !downloaded {
+            "<button disabled>Queued</button>".to_owned()
+        } else if let Some(c) = downloaded
+            .then(|| {
+                let book_dir = download_dir.join(&author_s).join(&title_s);
+                library.corrupt.get(&book_key(download_dir, &book_dir))
+            })
+            .flatten()
+        {
+            format!(
+                r#"<form method="post" action="/download/{}{query}">
+                    <button type="submit" title="{}">Re-download (corrupt)</button>
+                   </form>"#,
+                id.unwrap_or(0),
+                c.reason
+            )
         } else if downloaded {
-            "<button disabled>Downloaded</button>".to_owned()
+            let dir = book_key(download_dir, &download_dir.join(&author_s).join(&title_s));
+            format!(
+                r#"<a class="button" href="{}">Play</a>"#,
+                library::file_url(&dir, "audio.mp3", &query)
+            )
         } else if let Some(book_id) = id {
             format!(
-                r#"<form method="post" action="/download/{book_id}">
+                r#"<form method="post" action="/download/{book_id}{query}">
                     <button type="submit">Download</button>
                    </form>"#
             )
@@ -355,7 +513,56 @@ async fn list(
         )
         .unwrap();
     }
-    html.push_str("</div></body></html>");
+    if cached.is_err() {
+        let books = library::scan(download_dir).unwrap_or_else(|e| {
+            tracing::error!("list: cannot scan {}: {e:#}", download_dir.display());
+            Vec::new()
+        });
+        for book in books {
+            let cover_url = book.cover.as_deref().map_or_else(
+                || "https://www.storytel.com/images/nocover.png".to_owned(),
+                |c| library::file_url(&book.dir, c, &query),
+            );
+            write!(
+                &mut html,
+                r#"<div class="card">
+<img src="{cover_url}" alt="cover">
+<div class="info">
+  <div class="author">{}</div>
+  <div class="title">{}</div>
+  <div class="isbn">{}</div>
+</div>
+<div class="actions"><a class="button" href="{}">Play</a></div>
+</div>"#,
+                book.author,
+                book.title,
+                book.isbn.as_deref().unwrap_or(""),
+                library::file_url(&book.dir, "audio.mp3", &query)
+            )
+            .unwrap();
+        }
+    }
+    html.push_str("</div>");
+
+    let orphans = orphan_status(cfg, store);
+    if cfg.prune.policy != PrunePolicy::Keep && !orphans.is_empty() {
+        html.push_str(r#"<h2>No longer on your shelf</h2><ul class="orphans">"#);
+        for o in orphans {
+            let action = match (cfg.prune.policy, o.action_at) {
+                (PrunePolicy::Archive, Some(at)) => format!(", archived on {at}"),
+                (PrunePolicy::Delete, Some(at)) => format!(", deleted on {at}"),
+                _ => String::new(),
+            };
+            write!(
+                &mut html,
+                "<li>{} <span>(missing since {}{action})</span></li>",
+                o.path, o.first_seen
+            )
+            .unwrap();
+        }
+        html.push_str("</ul>");
+    }
+    html.push_str("</body></html>");
     HttpResponse::Ok()
         .content_type("text/html; charset=utf-8")
         .body(html)
@@ -363,161 +570,593 @@ async fn list(
 
 async fn download(
     path: web::Path<u64>,
-    data: web::Data<Mutex<ClientData>>,
-    download_dir: web::Data<PathBuf>,
-    progress: ProgressData,
+    q: web::Query<AccountQuery>,
+    accounts: web::Data<Accounts>,
 ) -> impl Responder {
     let id = path.into_inner();
+    let Some(ctx) = accounts.get(&q).cloned() else {
+        return unknown_account();
+    };
+    let back = format!("/{}", accounts.query(&ctx));
+    if SHUTDOWN.is_stopping() {
+        return HttpResponse::ServiceUnavailable().body("shutting down");
+    }
 
-    //  kick off a background blocking task; reply immediately
-    let progress_bg = progress.clone();
-    let download_dir = download_dir.get_ref().clone();
-    let data = data.clone();
-    tokio::spawn(async move {
-        // ---- quick section: read API, release lock ----
-        let (name, author, stream_url, cover_url) = {
-            let mut cd = data.lock().await;
-
-            let bookshelf = client_storytel_api::get_bookshelf(&mut cd).await.unwrap();
-            let (name, author) = bookshelf
-                .books
-                .iter()
-                .find_map(|b| {
-                    b.abook.as_ref().filter(|a| a.id == id).map(|_| {
-                        (
-                            b.book.name.clone(),
-                            b.book
-                                .authors_as_string
-                                .clone()
-                                .unwrap_or_else(|| "unknown".into()),
-                        )
-                    })
-                })
-                .unwrap_or_else(|| (format!("book_{id}"), "unknown".into()));
-
-            let cover_rel = bookshelf
-                .books
-                .iter()
-                .find_map(|b| {
-                    b.abook
-                        .as_ref()
-                        .filter(|a| a.id == id)
-                        .and_then(|_| b.cover.as_ref().or(b.book.cover.as_ref()).cloned())
-                })
-                .unwrap_or_else(|| "/images/nocover.png".into());
-
-            let url = client_storytel_api::get_stream_url(&mut cd, id)
-                .await
-                .unwrap();
-
-            (
-                name,
-                author,
-                url,
-                format!("https://www.storytel.com{cover_rel}"),
-            )
-        };
+    if let Err(e) = ctx.store.update(|s| {
+        s.queue.entry(id).or_insert_with(|| QueuedDownload {
+            requested_at: chrono::Utc::now().timestamp(),
+        });
+    }) {
+        tracing::error!("download: cannot queue id={id}: {e:#}");
+    }
+    //  kick off a background task; reply immediately
+    tokio::spawn(download_book(ctx, id));
 
-        tracing::info!("download: starting {author}/{name} (id={id})");
+    HttpResponse::SeeOther()
+        .insert_header((header::LOCATION, back))
+        .finish()
+}
 
-        let sanitize = |s: &str| s.replace(['/', '\\'], "_");
-        let author_s = sanitize(&author);
-        let title_s = sanitize(&name);
+/// How an on-demand download ended.
+enum Outcome {
+    /// Downloaded, or already on disk.
+    Done,
+    /// Trying again will not help, e.g. the book left the shelf.
+    Failed,
+    /// Interrupted by a shutdown, held by a sync pass or failed for a
+    /// passing reason such as a network problem.
+    RetryLater,
+}
 
-        let name_clone = name.clone();
+/// Runs one on-demand download and takes it off the queue unless it should
+/// be tried again later.
+async fn download_book(ctx: SyncContext, id: u64) {
+    // held until the queue is updated, so a shutdown waits for the whole book
+    let Some(_tracked) = SHUTDOWN.track() else {
+        return;
+    };
+    if let Outcome::RetryLater = fetch_on_demand(&ctx, id).await {
+        return;
+    }
+    if let Err(e) = ctx.store.update(|s| s.queue.remove(&id)) {
+        tracing::error!("download: cannot update queue: {e:#}");
+    }
+}
 
-        if crate::download::is_downloaded(&download_dir, &author_s, &title_s) {
+/// Downloads the book with abook id `id`.
+async fn fetch_on_demand(ctx: &SyncContext, id: u64) -> Outcome {
+    let SyncContext {
+        client,
+        cfg,
+        dl_dir,
+        progress: progress_bg,
+        locks,
+        store,
+        throttles,
+        shelf,
+        ..
+    } = ctx;
+    let cfg = &cfg.get();
+    let download_dir = dl_dir.get_ref().clone();
+    // the cached shelf usually has the book; fetch it only for books
+    // added since
+    let has_book = |s: &CachedShelf| {
+        s.shelf
+            .books
+            .iter()
+            .any(|b| b.abook.as_ref().is_some_and(|a| a.id == id))
+    };
+    let bookshelf = match shelf.get(client).await {
+        Ok(cached) if !has_book(&cached) => shelf.refresh(client).await,
+        res => res,
+    };
+    let bookshelf = match bookshelf {
+        Ok(cached) => cached.shelf,
+        Err(e) => {
+            tracing::warn!("download: cannot fetch bookshelf for id={id}, will retry: {e:#}");
+            return Outcome::RetryLater;
+        }
+    };
+    let Some(entry) = bookshelf
+        .books
+        .iter()
+        .find(|b| b.abook.as_ref().is_some_and(|a| a.id == id))
+    else {
+        tracing::error!("download: id={id} is not on the bookshelf");
+        webhooks::emit(
+            &cfg.webhooks,
+            Event::DownloadFailed {
+                book: BookInfo {
+                    abook_id: id,
+                    author: "unknown".into(),
+                    title: format!("book_{id}"),
+                    path: download_dir,
+                },
+                error: "not on the bookshelf".into(),
+            },
+        );
+        return Outcome::Failed;
+    };
+
+    let name = entry.book.name.clone();
+    let author = entry
+        .book
+        .authors_as_string
+        .clone()
+        .unwrap_or_else(|| "unknown".into());
+    let cover_rel = entry
+        .cover
+        .as_ref()
+        .or(entry.book.cover.as_ref())
+        .cloned()
+        .unwrap_or_else(|| "/images/nocover.png".into());
+    let cover_url = format!("https://www.storytel.com{cover_rel}");
+    let meta = BookMetadata::from_entry(id, entry);
+
+    let author_s = sanitize(&author);
+    let title_s = sanitize(&name);
+
+    let name_clone = name.clone();
+
+    let Some(_book_lock) = BookLocks::try_lock(locks, id) else {
+        tracing::info!("download: {author}/{name} is already being downloaded");
+        return Outcome::RetryLater;
+    };
+    let target = download_dir.join(&author_s).join(&title_s);
+    // books flagged corrupt may be downloaded again by hand
+    let corrupt = store
+        .get()
+        .corrupt
+        .contains_key(&book_key(&download_dir, &target));
+    if crate::download::is_downloaded(&download_dir, &author_s, &title_s) && !corrupt {
+        return Outcome::Done;
+    }
+
+    tracing::info!("download: starting {author}/{name} (id={id})");
+    tracing::debug!(
+        "download: id={id}, target={:?}, cover_url={}",
+        target,
+        cover_url
+    );
+    let info = BookInfo {
+        abook_id: id,
+        author: author.clone(),
+        title: name.clone(),
+        path: target.clone(),
+    };
+    webhooks::emit(&cfg.webhooks, Event::DownloadStarted { book: info.clone() });
+
+    let progress_inner = progress_bg.clone(); // move into closure
+
+    let mut last_print = Instant::now();
+    let result = fetch_book(
+        client,
+        &cfg.download_options(&throttles.on_demand),
+        id,
+        &target,
+        &cover_url,
+        Some(&meta),
+        move |done, total| {
+            // UI progress
+            if let Ok(mut map) = progress_inner.try_lock() {
+                map.insert(id, (done, total));
+            }
+
+            // console stats every minute
+            if last_print.elapsed().as_secs() >= 60 {
+                last_print = Instant::now();
+
+                let speed = done / 60;
+                let eta = total.and_then(|t| (t - done).checked_div(speed));
+
+                tracing::info!(
+                    "[{name_clone}] {} / {} @ {}/s {}",
+                    fmt_bytes(done),
+                    total.map_or_else(|| "?".into(), fmt_bytes),
+                    fmt_bytes(speed),
+                    eta.map(|s| format!("ETA {}", fmt_eta(s)))
+                        .unwrap_or_default()
+                );
+            }
+        },
+    )
+    .await;
+
+    // finished - remove entry
+    progress_bg.lock().await.remove(&id);
+    crate::verify::record(store, &download_dir, &target, &result);
+
+    if shutdown::is_interrupted(&result) {
+        tracing::info!("download: {author}/{name} will continue after a restart");
+        return Outcome::RetryLater;
+    }
+    match result {
+        Ok(size) => {
+            tracing::info!("download: finished {author}/{name}");
+            webhooks::emit(&cfg.webhooks, Event::DownloadFinished { book: info, size });
+            crate::media_server::notify_all(&cfg.media_servers).await;
+            Outcome::Done
+        }
+        Err(e) if crate::retry::is_transient(&e) => {
+            tracing::warn!("download: {author}/{name} failed, will retry: {e:#}");
+            Outcome::RetryLater
+        }
+        Err(e) => {
+            tracing::error!("download: {author}/{name} failed: {e:#}");
+            webhooks::emit(
+                &cfg.webhooks,
+                Event::DownloadFailed {
+                    book: info,
+                    error: format!("{e:#}"),
+                },
+            );
+            Outcome::Failed
+        }
+    }
+}
+
+/// How often queued on-demand downloads that did not finish are retried.
+const QUEUE_RETRY: Duration = Duration::from_secs(10 * 60);
+
+/// Restarts the on-demand downloads that were queued when the app stopped,
+/// once the account is logged in, and then retries the ones still queued
+/// every [`QUEUE_RETRY`].
+async fn resume_queue(ctx: SyncContext) {
+    loop {
+        while !ctx.client.is_logged_in() {
+            tokio::time::sleep(Duration::from_secs(30)).await;
+        }
+        if SHUTDOWN.is_stopping() {
             return;
         }
+        let queued: Vec<u64> = ctx.store.get().queue.keys().copied().collect();
+        if !queued.is_empty() {
+            tracing::info!(
+                "download: resuming {} queued downloads of account {}",
+                queued.len(),
+                ctx.account
+            );
+        }
+        for id in queued {
+            tokio::spawn(download_book(ctx.clone(), id));
+        }
+        tokio::time::sleep(QUEUE_RETRY).await;
+    }
+}
 
-        let target = download_dir.join(&author_s).join(&title_s);
-        let target_clone = target.clone();
-        tracing::debug!(
-            "download: id={id}, target={:?}, cover_url={}",
-            target_clone,
-            cover_url
-        );
+#[derive(Serialize)]
+struct SyncStatus {
+    enabled: bool,
+    running: bool,
+    warning: Option<String>,
+    next_run: Option<String>,
+    pass: Option<sync::PassSummary>,
+}
 
-        let progress_inner = progress_bg.clone(); // move into closure
+async fn sync_status(q: web::Query<AccountQuery>, accounts: web::Data<Accounts>) -> impl Responder {
+    let Some(SyncContext { cfg, state, .. }) = accounts.get(&q) else {
+        return unknown_account();
+    };
+    HttpResponse::Ok().json(SyncStatus {
+        enabled: cfg.get().sync_enabled,
+        running: state.is_running(),
+        warning: state.warning(),
+        next_run: state.next_run().map(|t| t.to_rfc3339()),
+        pass: state.pass(),
+    })
+}
 
-        let mut last_print = Instant::now();
-        client_storytel_api::download_stream_with_progress(
-            &stream_url,
-            &target_clone,
-            move |done, total| {
-                // UI progress
-                if let Ok(mut map) = progress_inner.try_lock() {
-                    map.insert(id, (done, total));
-                }
+/// Dry run: what the next sync pass would download or skip, and why.
+#[derive(Deserialize)]
+struct PlanQuery {
+    /// `?sizes=1` estimates download sizes, which asks Storytel twice per book.
+    #[serde(default)]
+    sizes: u8,
+}
 
-                // console stats every minute
-                if last_print.elapsed().as_secs() >= 60 {
-                    last_print = Instant::now();
-
-                    let speed = done / 60;
-                    let eta = total.and_then(|t| {
-                        if speed != 0 {
-                            Some((t - done) / speed)
-                        } else {
-                            None
-                        }
-                    });
-
-                    tracing::info!(
-                        "[{name_clone}] {} / {} @ {}/s {}",
-                        fmt_bytes(done),
-                        total.map_or_else(|| "?".into(), fmt_bytes),
-                        fmt_bytes(speed),
-                        eta.map(|s| format!("ETA {}", fmt_eta(s)))
-                            .unwrap_or_default()
-                    );
-                }
-            },
-        )
-        .await
-        .unwrap();
+async fn sync_plan(
+    q: web::Query<AccountQuery>,
+    plan_q: web::Query<PlanQuery>,
+    accounts: web::Data<Accounts>,
+) -> impl Responder {
+    let Some(SyncContext {
+        client,
+        cfg,
+        store,
+        shelf,
+        throttles,
+        dedupe_dirs,
+        ..
+    }) = accounts.get(&q)
+    else {
+        return unknown_account();
+    };
+    let res = async {
+        // planning leaves the cache alone; without one the shelf is fetched
+        // just for this plan
+        let shelf = match shelf.peek() {
+            Some(cached) => cached.shelf,
+            None => Arc::new(client.get_bookshelf().await?),
+        };
+        let sizes = (plan_q.sizes > 0).then_some(&*throttles.sync);
+        sync::dry_run(client, &shelf, &cfg.get(), &store.get(), dedupe_dirs, sizes).await
+    }
+    .await;
+    match res {
+        Ok(plan) => HttpResponse::Ok().json(plan),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
 
-        tracing::debug!("download: audio done, downloading cover {}", cover_url);
-        crate::download::download_cover(&cover_url, &target_clone)
-            .await
-            .unwrap();
+/// Starts a sync pass now. Browsers are redirected back to the bookshelf,
+/// API clients get `202 Accepted` or `409 Conflict` if a pass is running.
+async fn sync_now(
+    req: HttpRequest,
+    q: web::Query<AccountQuery>,
+    accounts: web::Data<Accounts>,
+) -> impl Responder {
+    let Some(ctx) = accounts.get(&q) else {
+        return unknown_account();
+    };
+    let started = ctx.state.trigger();
+    let wants_html = req
+        .headers()
+        .get(header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.contains("text/html"));
+    if wants_html {
+        HttpResponse::SeeOther()
+            .insert_header((header::LOCATION, format!("/{}", accounts.query(ctx))))
+            .finish()
+    } else if started {
+        HttpResponse::Accepted().json(serde_json::json!({ "status": "started" }))
+    } else {
+        HttpResponse::Conflict().json(serde_json::json!({ "status": "already_running" }))
+    }
+}
 
-        tracing::info!("download: finished {author}/{name}");
+/// Report of the last config reload; `null` before the first one.
+async fn reload_status(reloader: web::Data<Reloader>) -> impl Responder {
+    HttpResponse::Ok().json(reloader.last())
+}
 
-        // finished - remove entry
-        progress_bg.lock().await.remove(&id);
-    });
+/// Reloads the config file now. Answers `422 Unprocessable Entity` with the
+/// reason when the new config is invalid and was not applied.
+async fn reload_config(reloader: web::Data<Reloader>) -> impl Responder {
+    let report = reloader.reload().await;
+    if report.error.is_some() {
+        HttpResponse::UnprocessableEntity().json(report)
+    } else {
+        HttpResponse::Ok().json(report)
+    }
+}
 
-    HttpResponse::SeeOther()
-        .insert_header((header::LOCATION, "/"))
-        .finish()
+/// Serves a file of the account's local library, e.g. `audio.mp3`; works
+/// without a Storytel login.
+async fn files(
+    req: HttpRequest,
+    path: web::Path<String>,
+    q: web::Query<AccountQuery>,
+    accounts: web::Data<Accounts>,
+) -> impl Responder {
+    let Some(ctx) = accounts.get(&q) else {
+        return unknown_account();
+    };
+    library::serve(&req, &ctx.dl_dir, &path).await
 }
 
-pub async fn run(client: ClientData, cfg: &Config, host: &str, port: u16) {
-    let download_dir = cfg.download_dir.clone();
-    let client_data = web::Data::new(Mutex::new(client));
-    let download_dir_data = web::Data::new(download_dir.clone());
-    let progress: ProgressData = web::Data::new(Mutex::new(HashMap::new()));
+/// Fetches the bookshelf from Storytel now, ignoring the cache TTL. Browsers
+/// are redirected back to the bookshelf, API clients get the shelf size or
+/// `502 Bad Gateway`.
+async fn refresh_shelf(
+    req: HttpRequest,
+    q: web::Query<AccountQuery>,
+    accounts: web::Data<Accounts>,
+) -> impl Responder {
+    let Some(ctx) = accounts.get(&q) else {
+        return unknown_account();
+    };
+    let res = ctx.shelf.refresh(&ctx.client).await;
+    if let Err(e) = &res {
+        tracing::warn!("refresh: cannot fetch bookshelf of {}: {e:#}", ctx.account);
+    }
+    let wants_html = req
+        .headers()
+        .get(header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.contains("text/html"));
+    match res {
+        _ if wants_html => HttpResponse::SeeOther()
+            .insert_header((header::LOCATION, format!("/{}", accounts.query(ctx))))
+            .finish(),
+        Ok(cached) => HttpResponse::Ok().json(serde_json::json!({
+            "books": cached.shelf.books.len(),
+            "fetched_at": cached.fetched_at,
+        })),
+        Err(e) => HttpResponse::BadGateway().json(serde_json::json!({ "error": format!("{e:#}") })),
+    }
+}
 
-    if cfg.sync_enabled {
-        tokio::spawn(sync_worker(
-            client_data.clone(),
-            download_dir_data.clone(),
-            progress.clone(),
-        ));
+#[derive(Serialize)]
+struct OrphanStatus {
+    path: String,
+    first_seen: String,
+    /// When the configured policy will archive/delete the book.
+    action_at: Option<String>,
+}
+
+fn orphan_status(cfg: &Config, store: &StateStore) -> Vec<OrphanStatus> {
+    let fmt = |ts: i64| {
+        DateTime::from_timestamp(ts, 0)
+            .map(|t| t.with_timezone(&Local).format("%Y-%m-%d").to_string())
+            .unwrap_or_default()
+    };
+    let grace = i64::from(cfg.prune.grace_days) * 86_400;
+    let acts = matches!(cfg.prune.policy, PrunePolicy::Archive | PrunePolicy::Delete);
+    store
+        .get()
+        .orphans
+        .into_iter()
+        .map(|(path, o)| OrphanStatus {
+            path,
+            first_seen: fmt(o.first_seen),
+            action_at: acts.then(|| fmt(o.first_seen + grace)),
+        })
+        .collect()
+}
+
+async fn orphans(q: web::Query<AccountQuery>, accounts: web::Data<Accounts>) -> impl Responder {
+    let Some(SyncContext { cfg, store, .. }) = accounts.get(&q) else {
+        return unknown_account();
+    };
+    HttpResponse::Ok().json(orphan_status(&cfg.get(), store))
+}
+
+async fn metrics() -> impl Responder {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4; charset=utf-8")
+        .body(METRICS.render())
+}
+
+async fn healthz() -> impl Responder {
+    HttpResponse::Ok().content_type("text/plain").body("ok")
+}
+
+async fn readyz(accounts: web::Data<Accounts>) -> impl Responder {
+    let readiness = HEALTH.readiness(
+        accounts
+            .0
+            .iter()
+            .map(|c| (c.account.as_str(), c.dl_dir.as_path())),
+    );
+    if readiness.ready {
+        HttpResponse::Ok().json(readiness)
+    } else {
+        HttpResponse::ServiceUnavailable().json(readiness)
     }
+}
 
-    HttpServer::new(move || {
+/// Where the config came from, to read it again on reload.
+pub struct ConfigSource {
+    pub path: PathBuf,
+    /// `--set` options, applied again on every reload.
+    pub overrides: Vec<String>,
+}
+
+pub async fn run(
+    accounts: Vec<AccountInit>,
+    cfg: Config,
+    source: ConfigSource,
+    host: &str,
+    port: u16,
+) {
+    // bandwidth limits apply to all accounts together
+    let throttles = web::Data::new(Throttles::new(&cfg.bandwidth).unwrap_or_default());
+    let dirs: Vec<PathBuf> = accounts
+        .iter()
+        .map(|a| a.cfg.download_dir.clone())
+        .collect();
+
+    let mut contexts = Vec::new();
+    for AccountInit {
+        name,
+        cfg: account_cfg,
+        client,
+        store,
+    } in accounts
+    {
+        let dedupe_dirs = cfg.dedupe_dirs(&dirs, &account_cfg.download_dir);
+        let shelf = ShelfCache::open(
+            &account_cfg.state_dir(),
+            account_cfg.cache.bookshelf_ttl_secs,
+        );
+        let ctx = SyncContext {
+            account: name,
+            client: web::Data::new(client),
+            dl_dir: web::Data::new(account_cfg.download_dir.clone()),
+            cfg: web::Data::new(LiveConfig::new(account_cfg)),
+            progress: web::Data::new(Mutex::new(HashMap::new())),
+            state: web::Data::new(SyncState::default()),
+            locks: web::Data::new(BookLocks::default()),
+            store: web::Data::new(store),
+            throttles: throttles.clone(),
+            dedupe_dirs: web::Data::new(dedupe_dirs),
+            shelf: web::Data::new(shelf),
+        };
+        if !ctx.client.is_logged_in() {
+            tokio::spawn(sync::login_worker(ctx.clone()));
+        }
+        tokio::spawn(sync::sync_worker(ctx.clone()));
+        tokio::spawn(resume_queue(ctx.clone()));
+        contexts.push(ctx);
+    }
+    let grace_secs = cfg.shutdown.grace_secs;
+    let reloader = web::Data::new(Reloader::new(
+        &source.path,
+        source.overrides,
+        cfg,
+        contexts.clone(),
+        throttles,
+    ));
+    tokio::spawn(reload::watch(reloader.clone()));
+    #[cfg(unix)]
+    tokio::spawn(reload::on_sighup(reloader.clone()));
+    let accounts = web::Data::new(Accounts(contexts));
+    let at_exit = accounts.clone();
+
+    let server = HttpServer::new(move || {
         App::new()
-            .app_data(client_data.clone())
-            .app_data(download_dir_data.clone())
-            .app_data(progress.clone())
+            .app_data(accounts.clone())
+            .app_data(reloader.clone())
             .route("/", web::get().to(list))
             .route("/download/{id}", web::post().to(download))
+            .route("/api/sync", web::get().to(sync_status))
+            .route("/sync", web::post().to(sync_now))
+            .route("/refresh", web::post().to(refresh_shelf))
+            .route("/files/{path:.*}", web::get().to(files))
+            .route("/api/sync/plan", web::get().to(sync_plan))
+            .route("/api/orphans", web::get().to(orphans))
+            .route("/api/config/reload", web::get().to(reload_status))
+            .route("/api/config/reload", web::post().to(reload_config))
+            .route("/metrics", web::get().to(metrics))
+            .route("/healthz", web::get().to(healthz))
+            .route("/readyz", web::get().to(readyz))
     })
+    // SIGTERM/SIGINT are handled below so that the server and the downloads
+    // stop in parallel
+    .disable_signals()
+    .shutdown_timeout(grace_secs)
     .bind((host, port))
     .expect("bind failed")
-    .run()
-    .await
-    .expect("server failed");
+    .run();
+    let handle = server.handle();
+    let mut server = tokio::spawn(server);
+
+    tokio::select! {
+        res = &mut server => res.expect("server panicked").expect("server failed"),
+        () = shutdown::signal() => {
+            // everything up to the state flush has to fit into grace_secs
+            let grace = at_exit
+                .0
+                .first()
+                .map_or(0, |ctx| ctx.cfg.get().shutdown.grace_secs);
+            let deadline = tokio::time::Instant::now() + Duration::from_secs(grace);
+            tracing::info!("shutdown: stopping within {grace}s");
+            let stop_server = async {
+                if tokio::time::timeout_at(deadline, handle.stop(true))
+                    .await
+                    .is_err()
+                {
+                    tracing::warn!("shutdown: dropping open HTTP connections");
+                }
+            };
+            tokio::join!(stop_server, SHUTDOWN.drain(deadline));
+        }
+    }
+    for ctx in &at_exit.0 {
+        if let Err(e) = ctx.store.flush() {
+            tracing::error!("shutdown: cannot save state of {}: {e:#}", ctx.account);
+        }
+    }
+    tracing::info!("shutdown: done");
 }
@@ -0,0 +1,107 @@
+use serde::Serialize;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+
+#[derive(Serialize, Clone)]
+pub struct BookInfo {
+    pub abook_id: u64,
+    pub author: String,
+    pub title: String,
+    pub path: PathBuf,
+}
+
+/// Events POSTed as JSON to every configured webhook URL.
+#[derive(Serialize, Clone)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    DownloadStarted {
+        #[serde(flatten)]
+        book: BookInfo,
+    },
+    DownloadFinished {
+        #[serde(flatten)]
+        book: BookInfo,
+        size: u64,
+    },
+    DownloadFailed {
+        #[serde(flatten)]
+        book: BookInfo,
+        error: String,
+    },
+    SyncStarted {
+        already_synced: usize,
+        need_sync: usize,
+    },
+    SyncFinished {
+        already_synced: usize,
+        downloaded: usize,
+        failed: usize,
+    },
+    LoginFailed {
+        account: String,
+        email: String,
+        error: String,
+    },
+}
+
+#[derive(Serialize)]
+struct Payload<'a> {
+    #[serde(flatten)]
+    event: &'a Event,
+    timestamp: u64,
+}
+
+/// Delivers `event` to all `urls`, logging (but otherwise ignoring) failures.
+pub async fn send(urls: &[String], event: &Event) {
+    if urls.is_empty() {
+        return;
+    }
+    let client = match reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .build()
+    {
+        Ok(c) => c,
+        Err(e) => {
+            tracing::warn!("webhooks: cannot build client: {e}");
+            return;
+        }
+    };
+    let payload = Payload {
+        event,
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs()),
+    };
+    for url in urls {
+        match client.post(url).json(&payload).send().await {
+            Ok(resp) if resp.status().is_success() => {}
+            Ok(resp) => tracing::warn!("webhooks: {url} returned {}", resp.status()),
+            Err(e) => tracing::warn!("webhooks: POST to {url} failed: {e}"),
+        }
+    }
+}
+
+/// Queue of emitted events, delivered one after another by a single task so
+/// receivers see them in the order they happened.
+static OUTBOX: OnceLock<mpsc::UnboundedSender<(Vec<String>, Event)>> = OnceLock::new();
+
+/// Fire-and-forget variant of [`send`] for use on hot paths.
+pub fn emit(urls: &[String], event: Event) {
+    if urls.is_empty() {
+        return;
+    }
+    let outbox = OUTBOX.get_or_init(|| {
+        let (tx, mut rx) = mpsc::unbounded_channel::<(Vec<String>, Event)>();
+        tokio::spawn(async move {
+            while let Some((urls, event)) = rx.recv().await {
+                send(&urls, &event).await;
+            }
+        });
+        tx
+    });
+    if outbox.send((urls.to_vec(), event)).is_err() {
+        tracing::warn!("webhooks: delivery task has stopped, dropping event");
+    }
+}